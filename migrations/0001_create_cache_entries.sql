@@ -0,0 +1,18 @@
+-- Index of entries stored in R2 and KV, written by the worker on every store
+CREATE TABLE IF NOT EXISTS cache_entries (
+    key TEXT PRIMARY KEY,
+    method TEXT NOT NULL,
+    chain_id INTEGER NOT NULL,
+    from_block INTEGER,
+    to_block INTEGER,
+    block_hash TEXT,
+    store TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    created_at_ms INTEGER NOT NULL,
+    hits INTEGER NOT NULL DEFAULT 0,
+    last_hit_ms INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS idx_cache_entries_method ON cache_entries (chain_id, method);
+CREATE INDEX IF NOT EXISTS idx_cache_entries_blocks ON cache_entries (chain_id, to_block, from_block);
+CREATE INDEX IF NOT EXISTS idx_cache_entries_recency ON cache_entries (chain_id, COALESCE(last_hit_ms, created_at_ms));
@@ -0,0 +1,11 @@
+-- Entries scheduled for deletion by the maintenance cron
+ALTER TABLE cache_entries ADD COLUMN tombstoned_at_ms INTEGER;
+CREATE INDEX IF NOT EXISTS idx_cache_entries_tombstoned ON cache_entries (tombstoned_at_ms)
+    WHERE tombstoned_at_ms IS NOT NULL;
+
+-- Latest block seen by the maintenance cron, per chain
+CREATE TABLE IF NOT EXISTS chain_heads (
+    chain_id INTEGER PRIMARY KEY,
+    block_number INTEGER NOT NULL,
+    updated_at_ms INTEGER NOT NULL
+);
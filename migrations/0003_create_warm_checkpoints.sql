@@ -0,0 +1,7 @@
+-- Next block to warm, per cache warming job
+CREATE TABLE IF NOT EXISTS warm_checkpoints (
+    job TEXT PRIMARY KEY,
+    chain_id INTEGER NOT NULL,
+    next_block INTEGER NOT NULL,
+    updated_at_ms INTEGER NOT NULL
+);
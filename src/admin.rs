@@ -0,0 +1,449 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use worker::*;
+
+use crate::budget::{enforce_budgets, BudgetConfig};
+use crate::cache::{CacheManager, CacheTier};
+use crate::chains::ChainRegistry;
+use crate::handlers::HandlerRegistry;
+use crate::index::CacheIndex;
+use crate::utils::bearer_token_matches;
+use crate::warming::{warm, WarmingConfig};
+
+/// Upper bound on deletes per purge call, keeping one call within the subrequest limit
+const MAX_PURGE_DELETES: usize = 500;
+/// Upper bound on least recently used entries listed by a storage report
+const MAX_LRU_ENTRIES: usize = 100;
+
+/// `POST /admin/lookup` body
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LookupRequest {
+    chain: String,
+    method: String,
+    #[serde(default = "empty_params")]
+    params: Value,
+}
+
+fn empty_params() -> Value {
+    json!([])
+}
+
+/// `POST /admin/purge` body: either an exact `key`, or a `chain` with optional
+/// `method` and block range
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PurgeRequest {
+    key: Option<String>,
+    chain: Option<String>,
+    method: Option<String>,
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+}
+
+/// `POST /admin/reorg` body: drop every entry of `chain` at or above `from_block`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReorgRequest {
+    chain: String,
+    from_block: u64,
+}
+
+/// `POST /admin/storage` body: usage per method, optionally for one chain, and the
+/// `lru` least recently used entries
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StorageRequest {
+    chain: Option<String>,
+    lru: Option<usize>,
+}
+
+/// `POST /admin/budgets` body: enforce `CACHE_BUDGETS` now. Defaults to a dry run.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BudgetsRequest {
+    #[serde(default = "default_dry_run")]
+    dry_run: bool,
+}
+
+/// `POST /admin/warm` body: run the `CACHE_WARMING` jobs now, or only `job`.
+/// With `reset`, the jobs start over from their `from_block`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WarmRequest {
+    job: Option<String>,
+    #[serde(default)]
+    reset: bool,
+}
+
+fn default_dry_run() -> bool {
+    true
+}
+
+#[derive(Debug, PartialEq)]
+enum PurgeTarget {
+    Key(String),
+    Prefix {
+        chain: String,
+        method: Option<String>,
+        block_range: Option<(u64, u64)>,
+    },
+}
+
+impl PurgeRequest {
+    fn target(self) -> std::result::Result<PurgeTarget, String> {
+        let block_range = match (self.from_block, self.to_block) {
+            (None, None) => None,
+            (Some(from), Some(to)) if from <= to => Some((from, to)),
+            (Some(_), Some(_)) => return Err("from_block must not exceed to_block".to_string()),
+            _ => return Err("from_block and to_block must be given together".to_string()),
+        };
+
+        match (self.key, self.chain) {
+            (Some(key), None) if self.method.is_none() && block_range.is_none() => {
+                Ok(PurgeTarget::Key(key))
+            }
+            (Some(_), _) => Err("key cannot be combined with other fields".to_string()),
+            (None, Some(chain)) => Ok(PurgeTarget::Prefix {
+                chain,
+                method: self.method,
+                block_range,
+            }),
+            (None, None) => Err("either key or chain is required".to_string()),
+        }
+    }
+}
+
+/// Handle `/admin/*` routes. Requires `Authorization: Bearer <ADMIN_TOKEN>`;
+/// the routes are disabled when `ADMIN_TOKEN` is not set.
+pub async fn handle_admin(
+    mut req: Request,
+    env: &Env,
+    chain_registry: &ChainRegistry,
+    registry: &HandlerRegistry,
+) -> Result<Response> {
+    let admin_token = match env.secret("ADMIN_TOKEN") {
+        Ok(token) => token.to_string(),
+        Err(_) => return admin_error("Admin API is disabled", 404),
+    };
+
+    let authorization = req.headers().get("Authorization")?;
+    if !bearer_token_matches(authorization.as_deref(), &admin_token) {
+        return admin_error("Unauthorized", 401);
+    }
+
+    if req.method() != Method::Post {
+        return admin_error("Method not allowed", 405);
+    }
+
+    let path = req.path();
+    let body = req.bytes().await?;
+
+    match path.as_str() {
+        "/admin/lookup" => match serde_json::from_slice(&body) {
+            Ok(lookup) => handle_lookup(lookup, env, chain_registry, registry).await,
+            Err(e) => admin_error(&format!("Invalid lookup request: {e}"), 400),
+        },
+        "/admin/purge" => {
+            let target = serde_json::from_slice::<PurgeRequest>(&body)
+                .map_err(|e| e.to_string())
+                .and_then(PurgeRequest::target);
+            match target {
+                Ok(target) => handle_purge(target, env, chain_registry, registry).await,
+                Err(e) => admin_error(&format!("Invalid purge request: {e}"), 400),
+            }
+        }
+        "/admin/reorg" => match serde_json::from_slice(&body) {
+            Ok(reorg) => handle_reorg(reorg, env, chain_registry).await,
+            Err(e) => admin_error(&format!("Invalid reorg request: {e}"), 400),
+        },
+        "/admin/storage" => match serde_json::from_slice(&body) {
+            Ok(storage) => handle_storage(storage, env, chain_registry).await,
+            Err(e) => admin_error(&format!("Invalid storage request: {e}"), 400),
+        },
+        "/admin/budgets" => match serde_json::from_slice::<BudgetsRequest>(&body) {
+            Ok(budgets) => {
+                let config = BudgetConfig::from_env(env, &registry.methods())?;
+                let reports = enforce_budgets(env, chain_registry, &config, budgets.dry_run).await?;
+                Response::from_json(&json!({ "budgets": reports }))
+            }
+            Err(e) => admin_error(&format!("Invalid budgets request: {e}"), 400),
+        },
+        "/admin/warm" => match serde_json::from_slice(&body) {
+            Ok(warm_request) => handle_warm(warm_request, env, chain_registry, registry).await,
+            Err(e) => admin_error(&format!("Invalid warm request: {e}"), 400),
+        },
+        _ => admin_error("Not found", 404),
+    }
+}
+
+/// Resolve the cache key for a method call and report whether an entry is stored
+async fn handle_lookup(
+    lookup: LookupRequest,
+    env: &Env,
+    chain_registry: &ChainRegistry,
+    registry: &HandlerRegistry,
+) -> Result<Response> {
+    let cache_manager = match cache_manager_for(&lookup.chain, env, chain_registry)? {
+        Some(cache_manager) => cache_manager,
+        None => return admin_error(&format!("Unknown chain: {}", lookup.chain), 404),
+    };
+
+    let handler = match registry.get(&lookup.method) {
+        Some(handler) => handler,
+        None => return admin_error(&format!("Method is not cached: {}", lookup.method), 400),
+    };
+    if let Err(error) = handler.validate(&lookup.params) {
+        return admin_error(&error.message, 400);
+    }
+    let key = match handler.cache_key(&cache_manager, &lookup.params) {
+        Some(key) => key,
+        None => return admin_error("No cache key for these params", 400),
+    };
+
+    let tier = cache_manager
+        .method_policy(&lookup.method)
+        .tier
+        .unwrap_or_else(|| handler.tier());
+
+    let entry = match tier {
+        CacheTier::R2 => serde_json::to_value(cache_manager.inspect_entry(&key).await?)?,
+        // Memory entries live per isolate, so only this isolate's copy can be reported
+        CacheTier::Memory => match cache_manager.get_from_memory(&key) {
+            Some(value) => json!({ "size": serde_json::to_vec(&value)?.len() }),
+            None => Value::Null,
+        },
+    };
+
+    Response::from_json(&json!({
+        "key": key,
+        "tier": tier,
+        "exists": !entry.is_null(),
+        "entry": entry,
+    }))
+}
+
+async fn handle_purge(
+    target: PurgeTarget,
+    env: &Env,
+    chain_registry: &ChainRegistry,
+    registry: &HandlerRegistry,
+) -> Result<Response> {
+    let (chain, method, block_range) = match target {
+        PurgeTarget::Key(key) => {
+            // Any chain will do: they all share the same bucket binding
+            let cache_manager = match chain_registry.list(env).first() {
+                Some(chain) => CacheManager::new(env, chain)?,
+                None => return admin_error("No chain configured", 500),
+            };
+            cache_manager.delete_entry(&key).await?;
+            return Response::from_json(&json!({ "deleted": 1, "key": key }));
+        }
+        PurgeTarget::Prefix {
+            chain,
+            method,
+            block_range,
+        } => (chain, method, block_range),
+    };
+
+    let cache_manager = match cache_manager_for(&chain, env, chain_registry)? {
+        Some(cache_manager) => cache_manager,
+        None => return admin_error(&format!("Unknown chain: {chain}"), 404),
+    };
+
+    let methods: Vec<String> = match method {
+        Some(method) if registry.get(&method).is_some() => vec![method],
+        Some(method) => return admin_error(&format!("Method is not cached: {method}"), 400),
+        None => {
+            let mut methods: Vec<String> =
+                registry.methods().into_iter().map(str::to_string).collect();
+            methods.sort();
+            methods
+        }
+    };
+
+    let mut deleted = 0;
+    let mut skipped = 0;
+    let mut truncated = false;
+    for method in &methods {
+        let stats = cache_manager
+            .purge(method, block_range, MAX_PURGE_DELETES - deleted)
+            .await?;
+
+        deleted += stats.deleted;
+        skipped += stats.skipped;
+        if stats.truncated {
+            truncated = true;
+            break;
+        }
+    }
+
+    console_log!("Admin purge on chain {}: deleted {} entries", chain, deleted);
+
+    Response::from_json(&json!({
+        "deleted": deleted,
+        "skipped": skipped,
+        "truncated": truncated,
+    }))
+}
+
+/// Drop entries of blocks replaced by a reorg, using the index
+async fn handle_reorg(reorg: ReorgRequest, env: &Env, chain_registry: &ChainRegistry) -> Result<Response> {
+    let cache_manager = match cache_manager_for(&reorg.chain, env, chain_registry)? {
+        Some(cache_manager) => cache_manager,
+        None => return admin_error(&format!("Unknown chain: {}", reorg.chain), 404),
+    };
+
+    let stats = match cache_manager
+        .invalidate_from_block(reorg.from_block, MAX_PURGE_DELETES)
+        .await?
+    {
+        Some(stats) => stats,
+        None => return admin_error("Reorg invalidation needs the CACHE_INDEX D1 binding", 400),
+    };
+
+    console_log!(
+        "Admin reorg on chain {} from block {}: deleted {} entries",
+        reorg.chain,
+        reorg.from_block,
+        stats.deleted
+    );
+
+    Response::from_json(&stats)
+}
+
+/// Report stored entries, bytes and hits per method from the index
+async fn handle_storage(
+    storage: StorageRequest,
+    env: &Env,
+    chain_registry: &ChainRegistry,
+) -> Result<Response> {
+    let index = match CacheIndex::from_env(env) {
+        Some(index) => index,
+        None => return admin_error("Storage reports need the CACHE_INDEX D1 binding", 400),
+    };
+
+    let chain_id = match &storage.chain {
+        Some(chain) => match chain_registry.resolve_chain_id(chain) {
+            Some(chain_id) => Some(chain_id),
+            None => return admin_error(&format!("Unknown chain: {chain}"), 404),
+        },
+        None => None,
+    };
+
+    let usage = index.usage(chain_id).await?;
+    let least_recently_used = match storage.lru {
+        Some(limit) => Some(index.least_recently_used(chain_id, limit.min(MAX_LRU_ENTRIES)).await?),
+        None => None,
+    };
+
+    Response::from_json(&json!({
+        "usage": usage,
+        "least_recently_used": least_recently_used,
+    }))
+}
+
+/// Run warming jobs immediately, within the same per-run request limit as the cron
+async fn handle_warm(
+    warm_request: WarmRequest,
+    env: &Env,
+    chain_registry: &ChainRegistry,
+    registry: &HandlerRegistry,
+) -> Result<Response> {
+    let config = WarmingConfig::from_env(env, chain_registry)?;
+    if let Some(job) = warm_request.job.as_deref().filter(|job| !config.jobs.contains_key(*job)) {
+        return admin_error(&format!("Unknown warming job: {job}"), 404);
+    }
+    if CacheIndex::from_env(env).is_none() {
+        return admin_error("Cache warming needs the CACHE_INDEX D1 binding", 400);
+    }
+
+    let reports = warm(
+        env,
+        chain_registry,
+        registry,
+        &config,
+        warm_request.job.as_deref(),
+        warm_request.reset,
+    )
+    .await?;
+    Response::from_json(&json!({ "jobs": reports }))
+}
+
+fn cache_manager_for(
+    chain: &str,
+    env: &Env,
+    chain_registry: &ChainRegistry,
+) -> Result<Option<CacheManager>> {
+    match chain_registry
+        .resolve_chain_id(chain)
+        .and_then(|id| chain_registry.lookup(env, id))
+    {
+        Some(chain) => CacheManager::new(env, &chain).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn admin_error(message: &str, status: u16) -> Result<Response> {
+    Response::from_json(&json!({ "error": message })).map(|res| res.with_status(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(body: &str) -> std::result::Result<PurgeTarget, String> {
+        serde_json::from_str::<PurgeRequest>(body).unwrap().target()
+    }
+
+    #[test]
+    fn test_purge_targets() {
+        assert_eq!(
+            target(r#"{"key": "eth_getLogs/1/abc"}"#),
+            Ok(PurgeTarget::Key("eth_getLogs/1/abc".to_string()))
+        );
+        assert_eq!(
+            target(r#"{"chain": "polygon", "method": "eth_getLogs"}"#),
+            Ok(PurgeTarget::Prefix {
+                chain: "polygon".to_string(),
+                method: Some("eth_getLogs".to_string()),
+                block_range: None,
+            })
+        );
+        assert_eq!(
+            target(r#"{"chain": "1", "from_block": 100, "to_block": 200}"#),
+            Ok(PurgeTarget::Prefix {
+                chain: "1".to_string(),
+                method: None,
+                block_range: Some((100, 200)),
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_purge_requests() {
+        assert!(target(r#"{}"#).is_err());
+        assert!(target(r#"{"key": "k", "chain": "1"}"#).is_err());
+        assert!(target(r#"{"chain": "1", "from_block": 100}"#).is_err());
+        assert!(target(r#"{"chain": "1", "from_block": 200, "to_block": 100}"#).is_err());
+        assert!(serde_json::from_str::<PurgeRequest>(r#"{"prefix": "eth_getLogs/"}"#).is_err());
+    }
+
+    #[test]
+    fn test_index_requests() {
+        let reorg: ReorgRequest = serde_json::from_str(r#"{"chain": "1", "from_block": 100}"#).unwrap();
+        assert_eq!(reorg.from_block, 100);
+        assert!(serde_json::from_str::<ReorgRequest>(r#"{"chain": "1"}"#).is_err());
+
+        let budgets: BudgetsRequest = serde_json::from_str("{}").unwrap();
+        assert!(budgets.dry_run);
+
+        let storage: StorageRequest = serde_json::from_str(r#"{"lru": 10}"#).unwrap();
+        assert_eq!((storage.chain, storage.lru), (None, Some(10)));
+        assert!(serde_json::from_str::<StorageRequest>(r#"{"method": "eth_getLogs"}"#).is_err());
+
+        let warm_request: WarmRequest = serde_json::from_str(r#"{"job": "usdc"}"#).unwrap();
+        assert_eq!((warm_request.job.as_deref(), warm_request.reset), (Some("usdc"), false));
+        assert!(serde_json::from_str::<WarmRequest>(r#"{"from_block": 1}"#).is_err());
+    }
+}
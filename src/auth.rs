@@ -0,0 +1,247 @@
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use worker::*;
+
+use crate::ratelimit::BucketLimit;
+
+/// Header carrying the API key
+pub const API_KEY_HEADER: &str = "X-API-Key";
+/// Query parameter alternative, e.g. `/eth?key=...`
+pub const API_KEY_QUERY_PARAM: &str = "key";
+/// Secret (JSON object of key → settings) and KV namespace binding holding API keys
+const API_KEYS_BINDING: &str = "API_KEYS";
+
+/// Settings for one API key. Omitted lists allow everything.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    /// Label used in logs instead of the key itself
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Chain ids this key may use
+    #[serde(default)]
+    pub chains: Option<Vec<u64>>,
+    /// Methods this key may call
+    #[serde(default)]
+    pub methods: Option<Vec<String>>,
+    /// Only serve cached data; misses are rejected instead of spending upstream credits
+    #[serde(default)]
+    pub cache_only: bool,
+    /// Overrides the default bucket from `RATE_LIMITS`
+    #[serde(default)]
+    pub rate_limit: Option<BucketLimit>,
+}
+
+impl ApiKey {
+    pub fn allows_chain(&self, chain_id: u64) -> bool {
+        self.chains
+            .as_ref()
+            .map(|chains| chains.contains(&chain_id))
+            .unwrap_or(true)
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods
+            .as_ref()
+            .map(|methods| methods.iter().any(|m| m == method))
+            .unwrap_or(true)
+    }
+
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or("unnamed")
+    }
+
+    /// Check settings that deserialization alone doesn't catch
+    fn validate(&self) -> std::result::Result<(), String> {
+        match &self.rate_limit {
+            Some(limit) => limit.validate().map_err(|e| format!("rate_limit: {e}")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Why a request failed authentication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+}
+
+/// `REQUIRE_API_KEY = "true"` rejects requests without a key. Keys that are presented
+/// are always validated, so restricted keys keep their limits either way.
+pub fn api_key_required(env: &Env) -> bool {
+    env.var("REQUIRE_API_KEY")
+        .map(|v| v.to_string() == "true")
+        .unwrap_or(false)
+}
+
+/// The key presented by the client: `X-API-Key` header, `key` query parameter,
+/// or the path segment after the chain (`/eth/<key>`)
+pub fn presented_key(req: &Request, path_key: Option<&str>) -> Result<Option<String>> {
+    if let Some(key) = req.headers().get(API_KEY_HEADER)? {
+        return Ok(Some(key));
+    }
+
+    let query_key = req
+        .url()?
+        .query_pairs()
+        .find(|(name, _)| name == API_KEY_QUERY_PARAM)
+        .map(|(_, value)| value.into_owned());
+    if query_key.is_some() {
+        return Ok(query_key);
+    }
+
+    Ok(path_key.map(str::to_string))
+}
+
+/// Validate the presented key, returning its settings.
+/// `Ok(None)` means no key was presented, or none can be checked, and none is required.
+pub async fn authenticate(
+    env: &Env,
+    key: Option<&str>,
+) -> Result<std::result::Result<Option<ApiKey>, AuthError>> {
+    let key = match effective_key(key, key_store_bound(env)) {
+        Some(key) => key,
+        None if api_key_required(env) => return Ok(Err(AuthError::Missing)),
+        None => return Ok(Ok(None)),
+    };
+
+    match lookup_key(env, key).await? {
+        Some(api_key) => Ok(Ok(Some(api_key))),
+        None => Ok(Err(AuthError::Invalid)),
+    }
+}
+
+/// Whether an `API_KEYS` secret or KV namespace is configured
+fn key_store_bound(env: &Env) -> bool {
+    env.secret(API_KEYS_BINDING).is_ok() || env.kv(API_KEYS_BINDING).is_ok()
+}
+
+/// The key to validate. Without a key store, presented keys are ignored: deployments without
+/// keys keep serving URLs with anything after the chain anonymously.
+fn effective_key(key: Option<&str>, store_bound: bool) -> Option<&str> {
+    key.filter(|key| store_bound && !key.is_empty())
+}
+
+/// Rate limiting identity: a digest of the API key, or the client IP for anonymous requests
+pub fn client_id(req: &Request, key: Option<&str>) -> Result<String> {
+    if let Some(key) = key {
+        return Ok(format!("key:{}", &hex::encode(Sha256::digest(key.as_bytes()))[..16]));
+    }
+
+    Ok(match req.headers().get("CF-Connecting-IP")? {
+        Some(ip) => format!("ip:{ip}"),
+        None => "anonymous".to_string(),
+    })
+}
+
+/// Look a key up in the `API_KEYS` secret, then in the `API_KEYS` KV namespace
+async fn lookup_key(env: &Env, key: &str) -> Result<Option<ApiKey>> {
+    if let Ok(secret) = env.secret(API_KEYS_BINDING) {
+        let keys = parse_keys(&secret.to_string())?;
+        if let Some(api_key) = keys.get(key) {
+            return Ok(Some(api_key.clone()));
+        }
+    }
+
+    if let Ok(kv) = env.kv(API_KEYS_BINDING) {
+        let api_key = kv
+            .get(key)
+            .json::<ApiKey>()
+            .await
+            .map_err(|e| Error::RustError(format!("Invalid API key entry: {e}")))?;
+        if let Some(api_key) = &api_key {
+            api_key
+                .validate()
+                .map_err(|e| Error::RustError(format!("Invalid API key entry: {e}")))?;
+        }
+        return Ok(api_key);
+    }
+
+    Ok(None)
+}
+
+/// Parse the `API_KEYS` secret, reporting every invalid key at once. Errors name keys by
+/// their label, never by the key itself.
+fn parse_keys(raw: &str) -> Result<HashMap<String, ApiKey>> {
+    let keys: HashMap<String, ApiKey> =
+        serde_json::from_str(raw).map_err(|e| Error::RustError(format!("Invalid API_KEYS: {e}")))?;
+
+    let mut errors: Vec<String> = keys
+        .values()
+        .filter_map(|api_key| {
+            api_key
+                .validate()
+                .err()
+                .map(|e| format!("{}: {e}", api_key.label()))
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(keys)
+    } else {
+        errors.sort();
+        Err(Error::RustError(format!("Invalid API_KEYS: {}", errors.join("; "))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keys() {
+        let keys = parse_keys(
+            r#"{
+                "k1": {"name": "indexer", "chains": [1, 137], "methods": ["eth_getLogs"]},
+                "k2": {"cache_only": true, "rate_limit": {"capacity": 10, "refill_per_second": 1}}
+            }"#,
+        )
+        .unwrap();
+
+        let indexer = &keys["k1"];
+        assert_eq!(indexer.label(), "indexer");
+        assert!(indexer.allows_chain(137));
+        assert!(!indexer.allows_chain(8453));
+        assert!(indexer.allows_method("eth_getLogs"));
+        assert!(!indexer.allows_method("eth_sendRawTransaction"));
+        assert!(!indexer.cache_only);
+
+        let reader = &keys["k2"];
+        assert!(reader.allows_chain(8453));
+        assert!(reader.allows_method("eth_call"));
+        assert!(reader.cache_only);
+        assert_eq!(reader.rate_limit.unwrap().capacity, 10.0);
+    }
+
+    #[test]
+    fn test_keys_ignored_without_key_store() {
+        assert_eq!(effective_key(Some("anything"), false), None);
+        assert_eq!(effective_key(Some("k1"), true), Some("k1"));
+        assert_eq!(effective_key(Some(""), true), None);
+        assert_eq!(effective_key(None, true), None);
+    }
+
+    #[test]
+    fn test_unknown_key_fields_rejected() {
+        assert!(parse_keys(r#"{"k1": {"chain": [1]}}"#).is_err());
+    }
+
+    #[test]
+    fn test_invalid_key_rate_limits_rejected() {
+        let err = parse_keys(
+            r#"{
+                "k1": {"name": "empty", "rate_limit": {"capacity": 0, "refill_per_second": 1}},
+                "k2": {"name": "frozen", "rate_limit": {"capacity": 10, "refill_per_second": -1}},
+                "k3": {"name": "fine", "rate_limit": {"capacity": 10, "refill_per_second": 1}}
+            }"#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("empty: rate_limit: capacity and refill_per_second must be greater than 0"));
+        assert!(err.contains("frozen: rate_limit:"));
+        assert!(!err.contains("fine"));
+        assert!(!err.contains("k1"));
+    }
+}
@@ -0,0 +1,184 @@
+use serde::Deserialize;
+use std::cell::RefCell;
+use worker::*;
+
+/// Circuit breaker for the R2 tier, loaded from the `CACHE_CIRCUIT_BREAKER` variable:
+///
+/// ```json
+/// { "failure_threshold": 5, "open_seconds": 30 }
+/// ```
+///
+/// After `failure_threshold` consecutive R2 errors the tier is skipped for `open_seconds`.
+/// Requests are then let through again, and the first further failure reopens the circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BreakerConfig {
+    pub failure_threshold: u32,
+    pub open_seconds: u64,
+}
+
+impl BreakerConfig {
+    /// Load from `CACHE_CIRCUIT_BREAKER`; unset means R2 is always tried
+    pub fn from_env(env: &Env) -> Result<Option<Self>> {
+        match env.var("CACHE_CIRCUIT_BREAKER") {
+            Ok(raw) => Self::parse(&raw.to_string()),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn parse(raw: &str) -> Result<Option<Self>> {
+        if raw.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let config: Self = serde_json::from_str(raw)
+            .map_err(|e| Error::RustError(format!("Invalid CACHE_CIRCUIT_BREAKER: {e}")))?;
+        if config.failure_threshold == 0 || config.open_seconds == 0 {
+            return Err(Error::RustError(
+                "Invalid CACHE_CIRCUIT_BREAKER: failure_threshold and open_seconds must be greater than 0"
+                    .to_string(),
+            ));
+        }
+        Ok(Some(config))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct BreakerState {
+    consecutive_failures: u32,
+    /// Set once the circuit has opened; cleared by the next success
+    open_until_ms: Option<u64>,
+}
+
+impl BreakerState {
+    fn allows(&self, now: u64) -> bool {
+        self.open_until_ms.map(|until| now >= until).unwrap_or(true)
+    }
+
+    fn record_success(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Count a failure, returning whether it opened the circuit
+    fn record_failure(&mut self, config: &BreakerConfig, now: u64) -> bool {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+
+        // A failure right after the circuit was open reopens it immediately
+        let reopening = self.open_until_ms.is_some();
+        if reopening || self.consecutive_failures >= config.failure_threshold {
+            self.open_until_ms = Some(now + config.open_seconds * 1000);
+            return true;
+        }
+        false
+    }
+}
+
+thread_local! {
+    // R2 health as seen by this isolate
+    static R2_BREAKER: RefCell<BreakerState> = RefCell::new(BreakerState::default());
+}
+
+/// Tracks R2 errors and tells when the tier should be skipped
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CircuitBreaker {
+    config: Option<BreakerConfig>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: Option<BreakerConfig>) -> Self {
+        Self { config }
+    }
+
+    /// Whether R2 may be used; always true when no breaker is configured
+    pub fn allows(&self) -> bool {
+        if self.config.is_none() {
+            return true;
+        }
+        let now = Date::now().as_millis();
+        R2_BREAKER.with(|state| state.borrow().allows(now))
+    }
+
+    /// Record the outcome of an R2 operation
+    pub fn record<T>(&self, result: &Result<T>) {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return,
+        };
+
+        match result {
+            Ok(_) => R2_BREAKER.with(|state| state.borrow_mut().record_success()),
+            Err(e) => {
+                let now = Date::now().as_millis();
+                let opened = R2_BREAKER.with(|state| state.borrow_mut().record_failure(config, now));
+                if opened {
+                    console_log!(
+                        "R2 circuit breaker open for {}s after error: {:?}",
+                        config.open_seconds,
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: BreakerConfig = BreakerConfig {
+        failure_threshold: 3,
+        open_seconds: 30,
+    };
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(BreakerConfig::parse("").unwrap(), None);
+        assert_eq!(
+            BreakerConfig::parse(r#"{"failure_threshold": 3, "open_seconds": 30}"#).unwrap(),
+            Some(CONFIG)
+        );
+        assert!(BreakerConfig::parse(r#"{"failure_threshold": 0, "open_seconds": 30}"#).is_err());
+        assert!(BreakerConfig::parse(r#"{"failure_threshold": 3}"#).is_err());
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let mut state = BreakerState::default();
+        assert!(!state.record_failure(&CONFIG, 0));
+        assert!(!state.record_failure(&CONFIG, 0));
+        assert!(state.allows(0));
+
+        assert!(state.record_failure(&CONFIG, 1_000));
+        assert!(!state.allows(1_000));
+        assert!(!state.allows(30_999));
+        assert!(state.allows(31_000));
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let mut state = BreakerState::default();
+        state.record_failure(&CONFIG, 0);
+        state.record_failure(&CONFIG, 0);
+        state.record_success();
+        assert!(!state.record_failure(&CONFIG, 0));
+        assert!(state.allows(0));
+    }
+
+    #[test]
+    fn test_failure_after_cooldown_reopens() {
+        let mut state = BreakerState::default();
+        for _ in 0..3 {
+            state.record_failure(&CONFIG, 0);
+        }
+        assert!(state.allows(30_000));
+
+        // First trial after the cooldown fails: open again straight away
+        assert!(state.record_failure(&CONFIG, 30_000));
+        assert!(!state.allows(30_000));
+
+        // A successful trial closes it
+        state.record_success();
+        assert!(state.allows(30_000));
+    }
+}
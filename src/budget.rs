@@ -0,0 +1,446 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use worker::*;
+
+use crate::cache::CacheManager;
+use crate::chains::ChainRegistry;
+use crate::config::ALL_METHODS;
+use crate::index::{CacheIndex, EvictionOrder, IndexEntry, IndexFilter, IndexedKey};
+
+/// Upper bound on evictions per enforcement run, keeping one run within the subrequest limit
+pub const MAX_EVICTIONS: usize = 500;
+
+/// Storage limit for one method on a chain, or for the whole chain under `"*"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Budget {
+    pub max_bytes: u64,
+    #[serde(default)]
+    pub eviction: EvictionOrder,
+}
+
+/// Storage budgets loaded from the `CACHE_BUDGETS` variable:
+///
+/// ```json
+/// {
+///   "chains": {
+///     "1": {
+///       "*": { "max_bytes": 50000000000 },
+///       "debug_traceBlockByNumber": { "max_bytes": 10000000000, "eviction": "oldest_block" }
+///     }
+///   },
+///   "dry_run": false
+/// }
+/// ```
+///
+/// Sizes come from the D1 index, so budgets are only enforced when it is bound.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BudgetConfig {
+    #[serde(default)]
+    pub chains: HashMap<String, HashMap<String, Budget>>,
+    /// Report what the scheduled job would evict without deleting anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl BudgetConfig {
+    /// Load and validate budgets from `CACHE_BUDGETS`; unset means no budgets
+    pub fn from_env(env: &Env, known_methods: &[&str]) -> Result<Self> {
+        let raw = match env.var("CACHE_BUDGETS") {
+            Ok(var) => var.to_string(),
+            Err(_) => return Ok(Self::default()),
+        };
+
+        let config = Self::parse(&raw)?;
+        config.validate(known_methods)?;
+        Ok(config)
+    }
+
+    pub fn parse(raw: &str) -> Result<Self> {
+        if raw.trim().is_empty() {
+            return Ok(Self::default());
+        }
+
+        serde_json::from_str(raw).map_err(|e| Error::RustError(format!("Invalid CACHE_BUDGETS: {e}")))
+    }
+
+    /// Check the budgets for mistakes, reporting all of them at once
+    pub fn validate(&self, known_methods: &[&str]) -> Result<()> {
+        let mut errors = Vec::new();
+
+        for (chain_id, methods) in &self.chains {
+            if chain_id.parse::<u64>().is_err() {
+                errors.push(format!("chains.{chain_id}: chain id must be a decimal number"));
+            }
+            for (method, budget) in methods {
+                let location = format!("chains.{chain_id}.{method}");
+                if method != ALL_METHODS && !known_methods.contains(&method.as_str()) {
+                    errors.push(format!("{location}: unknown cached method"));
+                }
+                if budget.max_bytes == 0 {
+                    errors.push(format!("{location}: max_bytes must be greater than 0"));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            errors.sort();
+            Err(Error::RustError(format!("Invalid CACHE_BUDGETS: {}", errors.join("; "))))
+        }
+    }
+
+    /// Budgets in enforcement order: per chain, method budgets before the chain-wide one
+    fn ordered(&self) -> Vec<(u64, Option<String>, Budget)> {
+        let mut budgets: Vec<_> = self
+            .chains
+            .iter()
+            .filter_map(|(chain_id, methods)| chain_id.parse::<u64>().ok().map(|id| (id, methods)))
+            .flat_map(|(chain_id, methods)| {
+                methods.iter().map(move |(method, budget)| {
+                    let method = (method != ALL_METHODS).then(|| method.clone());
+                    (chain_id, method, *budget)
+                })
+            })
+            .collect();
+        budgets.sort_by(|a, b| (a.0, a.1.is_none(), &a.1).cmp(&(b.0, b.1.is_none(), &b.1)));
+        budgets
+    }
+}
+
+/// An entry chosen for eviction
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Eviction {
+    pub key: String,
+    /// Backend holding the entry: `kv` or `r2`
+    pub store: String,
+    pub size: u64,
+    pub to_block: Option<u64>,
+    pub last_used_ms: u64,
+}
+
+/// Outcome of enforcing one budget
+#[derive(Debug, Clone, Serialize)]
+pub struct BudgetReport {
+    pub chain_id: u64,
+    /// `"*"` for a chain-wide budget
+    pub method: String,
+    pub max_bytes: u64,
+    /// Usage after the evictions of the budgets enforced before this one, dry runs included
+    pub used_bytes: u64,
+    pub freed_bytes: u64,
+    pub evicted: Vec<Eviction>,
+    pub dry_run: bool,
+    /// The run's eviction limit was reached before the budget was met
+    pub truncated: bool,
+}
+
+/// Pick candidates, in eviction order, until usage fits the budget.
+/// Returns the chosen entries and whether candidates ran out first.
+fn plan_evictions(candidates: Vec<IndexEntry>, used_bytes: u64, max_bytes: u64) -> (Vec<Eviction>, bool) {
+    let mut remaining = used_bytes;
+    let mut evictions = Vec::new();
+
+    for entry in candidates {
+        if remaining <= max_bytes {
+            break;
+        }
+        remaining = remaining.saturating_sub(entry.size);
+        evictions.push(Eviction {
+            last_used_ms: entry.last_hit_ms.unwrap_or(entry.created_at_ms),
+            key: entry.key,
+            store: entry.store,
+            size: entry.size,
+            to_block: entry.to_block,
+        });
+    }
+
+    (evictions, remaining > max_bytes)
+}
+
+/// Evictions earlier budgets of a dry run planned but didn't carry out. The index still
+/// counts those entries, so later budgets covering them must discount them: a method budget
+/// its own method's, the chain-wide budget every method's of its chain.
+#[derive(Debug, Default)]
+struct PlannedEvictions {
+    /// (chain, budget method) → (planned bytes, planned keys)
+    budgets: HashMap<(u64, String), (u64, HashSet<String>)>,
+}
+
+impl PlannedEvictions {
+    fn add(&mut self, chain_id: u64, method: &str, evictions: &[Eviction]) {
+        let (bytes, keys) = self.budgets.entry((chain_id, method.to_string())).or_default();
+        for eviction in evictions {
+            if keys.insert(eviction.key.clone()) {
+                *bytes += eviction.size;
+            }
+        }
+    }
+
+    /// Plans of the budgets whose entries a budget for `method` (`None` for `"*"`) also counts
+    fn covered<'a>(
+        &'a self,
+        chain_id: u64,
+        method: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a (u64, HashSet<String>)> + 'a {
+        self.budgets
+            .iter()
+            .filter(move |((planned_chain, planned_method), _)| {
+                *planned_chain == chain_id && method.is_none_or(|method| method == planned_method)
+            })
+            .map(|(_, planned)| planned)
+    }
+
+    fn bytes(&self, chain_id: u64, method: Option<&str>) -> u64 {
+        self.covered(chain_id, method).map(|(bytes, _)| bytes).sum()
+    }
+
+    fn count(&self, chain_id: u64, method: Option<&str>) -> usize {
+        self.covered(chain_id, method).map(|(_, keys)| keys.len()).sum()
+    }
+
+    /// Candidates not already planned for eviction, at most `limit`
+    fn exclude(
+        &self,
+        chain_id: u64,
+        method: Option<&str>,
+        candidates: Vec<IndexEntry>,
+        limit: usize,
+    ) -> Vec<IndexEntry> {
+        candidates
+            .into_iter()
+            .filter(|entry| !self.covered(chain_id, method).any(|(_, keys)| keys.contains(&entry.key)))
+            .take(limit)
+            .collect()
+    }
+}
+
+/// Enforce every configured budget, evicting at most `MAX_EVICTIONS` entries in total.
+/// With `dry_run`, nothing is deleted and the reports show what would be evicted.
+pub async fn enforce_budgets(
+    env: &Env,
+    chain_registry: &ChainRegistry,
+    config: &BudgetConfig,
+    dry_run: bool,
+) -> Result<Vec<BudgetReport>> {
+    let index = match CacheIndex::from_env(env) {
+        Some(index) => index,
+        None => return Err("Storage budgets need the CACHE_INDEX D1 binding".into()),
+    };
+
+    let mut reports = Vec::new();
+    let mut allowance = MAX_EVICTIONS;
+    let mut planned = PlannedEvictions::default();
+
+    for (chain_id, method, budget) in config.ordered() {
+        let filter = IndexFilter {
+            chain_id,
+            method: method.clone(),
+            block_range: None,
+        };
+        // A chain-wide budget also counts the entries of the chain's method budgets, which a
+        // dry run leaves in place
+        let used_bytes = index
+            .used_bytes(&filter)
+            .await?
+            .saturating_sub(planned.bytes(chain_id, method.as_deref()));
+
+        let mut report = BudgetReport {
+            chain_id,
+            method: method.clone().unwrap_or_else(|| ALL_METHODS.to_string()),
+            max_bytes: budget.max_bytes,
+            used_bytes,
+            freed_bytes: 0,
+            evicted: Vec::new(),
+            dry_run,
+            truncated: false,
+        };
+
+        if used_bytes > budget.max_bytes {
+            let candidates = index
+                .eviction_candidates(
+                    &filter,
+                    budget.eviction,
+                    allowance + planned.count(chain_id, method.as_deref()),
+                )
+                .await?;
+            let candidates = planned.exclude(chain_id, method.as_deref(), candidates, allowance);
+            let (evictions, short) = plan_evictions(candidates, used_bytes, budget.max_bytes);
+            report.truncated = short;
+            report.freed_bytes = evictions.iter().map(|eviction| eviction.size).sum();
+            allowance -= evictions.len();
+
+            if dry_run {
+                planned.add(chain_id, &report.method, &evictions);
+            } else if !evictions.is_empty() {
+                evict(env, chain_registry, chain_id, &evictions).await?;
+            }
+            report.evicted = evictions;
+
+            console_log!(
+                "Budget for chain {} {}: {} of {} bytes used, {} {} entries ({} bytes)",
+                report.chain_id,
+                report.method,
+                report.used_bytes,
+                report.max_bytes,
+                if dry_run { "would evict" } else { "evicted" },
+                report.evicted.len(),
+                report.freed_bytes
+            );
+        }
+
+        reports.push(report);
+        if allowance == 0 {
+            break;
+        }
+    }
+
+    Ok(reports)
+}
+
+async fn evict(
+    env: &Env,
+    chain_registry: &ChainRegistry,
+    chain_id: u64,
+    evictions: &[Eviction],
+) -> Result<()> {
+    let chain = match chain_registry.lookup(env, chain_id) {
+        Some(chain) => chain,
+        None => return Err(format!("Budget for unknown chain {chain_id}").into()),
+    };
+    let entries = evictions
+        .iter()
+        .map(|eviction| IndexedKey {
+            key: eviction.key.clone(),
+            store: eviction.store.clone(),
+        })
+        .collect();
+    CacheManager::new(env, &chain)?.delete_indexed(entries).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHODS: &[&str] = &["eth_getLogs", "debug_traceBlockByNumber"];
+
+    fn entry(key: &str, size: u64) -> IndexEntry {
+        IndexEntry {
+            key: key.to_string(),
+            method: "eth_getLogs".to_string(),
+            chain_id: 1,
+            from_block: None,
+            to_block: Some(100),
+            block_hash: None,
+            store: "r2".to_string(),
+            size,
+            created_at_ms: 1_000,
+            hits: 0,
+            last_hit_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_budgets() {
+        let config = BudgetConfig::parse(
+            r#"{
+                "chains": {
+                    "1": {
+                        "*": {"max_bytes": 1000},
+                        "debug_traceBlockByNumber": {"max_bytes": 100, "eviction": "oldest_block"}
+                    }
+                },
+                "dry_run": true
+            }"#,
+        )
+        .unwrap();
+        config.validate(METHODS).unwrap();
+        assert!(config.dry_run);
+
+        let ordered = config.ordered();
+        assert_eq!(
+            ordered,
+            vec![
+                (
+                    1,
+                    Some("debug_traceBlockByNumber".to_string()),
+                    Budget { max_bytes: 100, eviction: EvictionOrder::OldestBlock }
+                ),
+                (1, None, Budget { max_bytes: 1000, eviction: EvictionOrder::Lru }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_budgets() {
+        assert!(BudgetConfig::parse(r#"{"chains": {"1": {"*": {"max_bytes": 1, "order": "lru"}}}}"#).is_err());
+
+        let config = BudgetConfig::parse(
+            r#"{"chains": {"eth": {"eth_foo": {"max_bytes": 0}}}}"#,
+        )
+        .unwrap();
+        let message = config.validate(METHODS).unwrap_err().to_string();
+        assert!(message.contains("chains.eth: chain id must be a decimal number"));
+        assert!(message.contains("chains.eth.eth_foo: unknown cached method"));
+        assert!(message.contains("chains.eth.eth_foo: max_bytes must be greater than 0"));
+    }
+
+    #[test]
+    fn test_plan_evictions() {
+        let candidates = vec![entry("a", 40), entry("b", 30), entry("c", 50)];
+
+        // 120 used, budget 60: evicting a and b frees 70
+        let (evictions, short) = plan_evictions(candidates.clone(), 120, 60);
+        let keys: Vec<_> = evictions.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert_eq!(evictions[0].last_used_ms, 1_000);
+        assert!(!short);
+
+        // Within budget: nothing to do
+        assert_eq!(plan_evictions(candidates.clone(), 60, 60), (Vec::new(), false));
+
+        // Not enough candidates (eviction limit reached)
+        let (evictions, short) = plan_evictions(candidates, 500, 60);
+        assert_eq!(evictions.len(), 3);
+        assert!(short);
+    }
+
+    #[test]
+    fn test_dry_run_discounts_planned_evictions() {
+        // A method budget planned to evict a and b; the chain-wide budget sees 200 bytes used
+        let (method_evictions, _) = plan_evictions(vec![entry("a", 40), entry("b", 30)], 170, 100);
+        let mut planned = PlannedEvictions::default();
+        planned.add(1, "eth_getLogs", &method_evictions);
+        assert_eq!(planned.bytes(1, None), 70);
+        assert_eq!(planned.bytes(2, None), 0);
+
+        let candidates = vec![entry("a", 40), entry("c", 50), entry("b", 30), entry("d", 60)];
+        let candidates = planned.exclude(1, None, candidates, MAX_EVICTIONS);
+        let (evictions, short) = plan_evictions(candidates, 200 - planned.bytes(1, None), 100);
+        let keys: Vec<_> = evictions.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, vec!["c"]);
+        assert!(!short);
+
+        // Other chains are unaffected
+        assert_eq!(planned.exclude(2, None, vec![entry("a", 40)], MAX_EVICTIONS).len(), 1);
+    }
+
+    #[test]
+    fn test_dry_run_method_budgets_are_independent() {
+        let mut planned = PlannedEvictions::default();
+        planned.add(1, "eth_getLogs", &plan_evictions(vec![entry("a", 40)], 140, 100).0);
+        planned.add(1, "debug_traceBlockByNumber", &plan_evictions(vec![entry("t", 25)], 125, 100).0);
+
+        // A second method budget on the chain only discounts its own method's plan
+        assert_eq!(planned.bytes(1, Some("debug_traceBlockByNumber")), 25);
+        assert_eq!(planned.bytes(1, Some("eth_call")), 0);
+        assert_eq!(planned.exclude(1, Some("eth_call"), vec![entry("a", 40)], MAX_EVICTIONS).len(), 1);
+
+        // The chain-wide budget discounts both
+        assert_eq!(planned.bytes(1, None), 65);
+        assert_eq!(planned.count(1, None), 2);
+    }
+}
@@ -4,21 +4,165 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use worker::*;
 
+use crate::breaker::{BreakerConfig, CircuitBreaker};
+use crate::chains::ChainConfig;
+use crate::config::{CachePolicyConfig, FinalityMode, MethodPolicy, DEFAULT_MEMORY_TTL_SECONDS};
+use crate::edge::EdgeCache;
+use crate::envelope::{self, ContentEncoding, DecodedEntry, EntryHeader, EntryMetadata};
+use crate::metrics::{self, Metric};
+use crate::handlers::HandlerRegistry;
+use crate::index::{self, CacheIndex, IndexEntry, IndexFilter, IndexedKey};
+use crate::rpc::RpcRequest;
+use crate::upstream::proxy_request;
 use crate::utils::{generate_cache_key, parse_hex_to_u64};
 
 #[derive(Clone)]
-struct CachedBlock {
+struct CachedEntry {
     data: Value,
     timestamp_ms: u64,
+    ttl_ms: u64,
+}
+
+/// KV namespace holding small entries of methods whose policy sets `kv_max_bytes`
+const KV_BINDING: &str = "CACHE_KV";
+
+/// R2 custom metadata recording the blocks an entry covers, used for block range purges
+const FROM_BLOCK_METADATA: &str = "from_block";
+const TO_BLOCK_METADATA: &str = "to_block";
+/// R2 custom metadata naming the entry's compression, for inspection
+const ENCODING_METADATA: &str = "content_encoding";
+/// R2 custom metadata holding the SHA-256 of the uncompressed payload, verified on read
+const CHECKSUM_METADATA: &str = "checksum";
+
+thread_local! {
+    // In-memory cache shared by all requests handled by this isolate
+    static MEMORY_CACHE: RefCell<HashMap<String, CachedEntry>> = RefCell::new(HashMap::new());
 }
 
 pub struct CacheManager {
     chain_id: String,
+    chain: ChainConfig,
     r2_bucket: Option<Bucket>,
-    block_distance_config: HashMap<String, u64>,
-    default_block_distance: u64,
-    // In-memory cache for blocks with 2-second TTL
-    block_cache: RefCell<HashMap<String, CachedBlock>>,
+    kv: Option<kv::KvStore>,
+    policy: CachePolicyConfig,
+    r2_breaker: CircuitBreaker,
+    /// Workers Cache API copy of R2 entries, consulted before R2
+    edge: Option<EdgeCache>,
+    /// Edge copies waiting to be written once the response is sent (see `flush_deferred_writes`)
+    edge_writes: RefCell<Vec<(String, Vec<u8>)>>,
+    /// Entries read in an older format, rewritten once the response is sent
+    migrations: RefCell<Vec<PendingMigration>>,
+    /// D1 index of stored entries
+    index: Option<CacheIndex>,
+}
+
+/// An entry read in an older format, waiting to be rewritten in the current one
+struct PendingMigration {
+    cache_key: String,
+    value_json: Vec<u8>,
+    encoding: ContentEncoding,
+    metadata: EntryMetadata,
+    from_version: u8,
+}
+
+/// Whether a request or response may be served from or written to the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cacheability {
+    /// Never cache (special block tags, pending data)
+    Never,
+    /// Immutable data, safe to cache as soon as it exists
+    Final,
+    /// Cache once the block is at least `block_distance` behind the tip
+    AtBlock(u64),
+}
+
+/// Where a cached method keeps its entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheTier {
+    /// Per-isolate memory with a short TTL, for data that changes with the tip
+    Memory,
+    /// Permanent R2 storage
+    R2,
+}
+
+/// Size and metadata of a stored entry
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryInfo {
+    /// Backend holding the entry: `kv` or `r2`
+    pub store: &'static str,
+    pub size: u32,
+    /// Upload time; KV does not record one
+    pub uploaded_ms: Option<u64>,
+    pub metadata: HashMap<String, String>,
+    /// Envelope header; absent for entries written before headers existed
+    pub header: Option<EntryHeader>,
+}
+
+/// Outcome of a bulk purge
+#[derive(Debug, Default, Serialize)]
+pub struct PurgeStats {
+    pub deleted: usize,
+    /// Entries without block metadata, which never match a block range
+    pub skipped: usize,
+    /// The delete limit was reached before all matching entries were removed
+    pub truncated: bool,
+    /// Matching entries left marked in the index for the maintenance cron to delete
+    pub tombstoned: usize,
+    /// Keys deleted, to be removed from the index
+    #[serde(skip)]
+    deleted_keys: Vec<String>,
+}
+
+impl PurgeStats {
+    /// Whether a listed entry matches the purge; entries without block metadata never
+    /// match a block range and are counted as skipped
+    fn selects(&mut self, metadata: &HashMap<String, String>, block_range: Option<(u64, u64)>) -> bool {
+        let range = match block_range {
+            Some(range) => range,
+            None => return true,
+        };
+        match entry_block_range(metadata) {
+            Some(entry_range) => ranges_overlap(entry_range, range),
+            None => {
+                self.skipped += 1;
+                false
+            }
+        }
+    }
+}
+
+impl CacheTier {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheTier::Memory => "memory",
+            CacheTier::R2 => "r2",
+        }
+    }
+}
+
+/// How a response relates to the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// Served from the cache
+    Hit,
+    /// Looked up, not found, fetched from upstream
+    Miss,
+    /// The cache was not consulted (uncached method, non-final data, caching disabled)
+    Bypass,
+    /// Non-final data served from the short-lived memory tier
+    Stale,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Bypass => "BYPASS",
+            CacheStatus::Stale => "STALE",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,68 +176,84 @@ pub struct GetLogsRequest {
 }
 
 impl CacheManager {
-    pub fn new(env: &Env, chain_id: &str) -> Result<Self> {
+    pub fn new(env: &Env, chain: &ChainConfig) -> Result<Self> {
         // Get R2 bucket for logs cache
         let r2_bucket = env.bucket("LOGS_CACHE").ok();
+        let kv = env.kv(KV_BINDING).ok();
 
-        // Load block distance configuration
-        let default_block_distance = env
-            .var("DEFAULT_BLOCK_DISTANCE")
-            .ok()
-            .and_then(|v| v.to_string().parse::<u64>().ok())
-            .unwrap_or(100);
-
-        let block_distance_config: HashMap<String, u64> = env
-            .var("CHAIN_BLOCK_DISTANCES")
-            .ok()
-            .and_then(|v| serde_json::from_str(&v.to_string()).ok())
-            .unwrap_or_default();
+        // Load per-method caching policy, rejecting invalid configuration up front
+        let policy = CachePolicyConfig::from_env(env, &HandlerRegistry::new().methods())?;
+        let r2_breaker = CircuitBreaker::new(BreakerConfig::from_env(env)?);
+        let edge = EdgeCache::from_env(env)?;
+        let index = CacheIndex::from_env(env);
 
         console_log!(
             "CacheManager initialized for chain {} with block distance {}",
-            chain_id,
-            default_block_distance
+            chain.id,
+            chain.block_distance
         );
 
         Ok(Self {
-            chain_id: chain_id.to_string(),
+            chain_id: chain.id_str(),
+            chain: chain.clone(),
             r2_bucket,
-            block_distance_config,
-            default_block_distance,
-            block_cache: RefCell::new(HashMap::new()),
+            kv,
+            policy,
+            r2_breaker,
+            edge,
+            edge_writes: RefCell::new(Vec::new()),
+            migrations: RefCell::new(Vec::new()),
+            index,
         })
     }
 
-    /// Get the block distance for the current chain
-    fn get_block_distance(&self) -> u64 {
-        self.block_distance_config
-            .get(&self.chain_id)
-            .copied()
-            .unwrap_or(self.default_block_distance)
+    /// Configuration of the chain this manager caches for
+    pub fn chain(&self) -> &ChainConfig {
+        &self.chain
     }
 
-    /// Check if logs should be cached based on block distance from tip
-    pub async fn should_cache_logs(
-        &self,
-        from_block: &str,
-        to_block: &str,
-        env: &Env,
-    ) -> Result<bool> {
-        // Skip caching if using special tags like "latest" or "pending"
-        if from_block == "latest"
-            || from_block == "pending"
-            || to_block == "latest"
-            || to_block == "pending"
-        {
-            return Ok(false);
+    /// Effective caching policy for a method on the current chain
+    pub fn method_policy(&self, method: &str) -> MethodPolicy {
+        self.policy
+            .resolve(self.chain.base_policy(method), &self.chain_id, method)
+    }
+
+    /// Resolve a cacheability decision, checking the chain tip when the policy requires it
+    pub async fn is_cacheable(&self, cacheability: Cacheability, policy: &MethodPolicy) -> bool {
+        match cacheability {
+            Cacheability::Never => false,
+            Cacheability::Final => true,
+            Cacheability::AtBlock(block_number) => {
+                let block_distance = required_block_distance(policy, self.chain.block_distance);
+                self.should_cache_by_block_number(block_number, block_distance)
+                    .await
+                    .unwrap_or(false)
+            }
         }
+    }
+
+    /// Get current block number from the RPC
+    pub async fn get_current_block_number(&self) -> Result<u64> {
+        let rpc_request = RpcRequest {
+            jsonrpc: "2.0".to_string(),
+            method: "eth_blockNumber".to_string(),
+            params: serde_json::json!([]),
+            id: Some(serde_json::json!(1)),
+        };
 
-        // Parse block numbers
-        let from = parse_hex_to_u64(from_block)?;
-        let to = parse_hex_to_u64(to_block)?;
+        let response = proxy_request(&rpc_request, &self.chain).await?;
 
+        if let Some(result) = response.result.as_ref().and_then(|v| v.as_str()) {
+            parse_hex_to_u64(result)
+        } else {
+            Err("Failed to get block number".into())
+        }
+    }
+
+    /// Check if a specific block number should be cached
+    pub async fn should_cache_by_block_number(&self, block_number: u64, block_distance: u64) -> Result<bool> {
         // Get current block number
-        let current_block = match self.get_current_block_number(env).await {
+        let current_block = match self.get_current_block_number().await {
             Ok(num) => num,
             Err(e) => {
                 console_log!("Failed to get current block number: {:?}", e);
@@ -101,440 +261,870 @@ impl CacheManager {
             }
         };
 
-        let block_distance = self.get_block_distance();
-
-        // Cache only if the requested range is at least block_distance blocks behind current
-        let should_cache = to + block_distance <= current_block;
-
+        // Cache only if block is old enough
+        let should_cache = block_number + block_distance <= current_block;
+        
         console_log!(
-            "Block cache check: from={}, to={}, current={}, distance={}, should_cache={}",
-            from,
-            to,
-            current_block,
-            block_distance,
-            should_cache
+            "Block number {} check: current={}, distance={}, should_cache={}",
+            block_number, current_block, block_distance, should_cache
         );
-
+        
         Ok(should_cache)
     }
 
-    /// Get current block number from the RPC
-    async fn get_current_block_number(&self, env: &Env) -> Result<u64> {
-        let upstream_url = env.var(&format!("UPSTREAM_RPC_URL_{}", self.chain_id))?.to_string();
-
-        let rpc_request = serde_json::json!({
-            "jsonrpc": "2.0",
-            "method": "eth_blockNumber",
-            "params": [],
-            "id": 1
-        });
+    /// Whether a tier can be used right now: R2 needs its binding and a closed circuit breaker
+    pub fn tier_available(&self, tier: CacheTier) -> bool {
+        match tier {
+            CacheTier::Memory => true,
+            CacheTier::R2 => self.r2_bucket.is_some() && self.r2_breaker.allows(),
+        }
+    }
+
+    /// Get a cached value from the given tier. R2-tier lookups try the edge cache first,
+    /// then KV when the policy's size hint says entries may live there, then R2.
+    /// R2 entries in an older format are queued for rewriting with `encoding`.
+    pub async fn get_from_cache(
+        &self,
+        tier: CacheTier,
+        cache_key: &str,
+        policy: &MethodPolicy,
+        encoding: ContentEncoding,
+    ) -> Result<Option<Value>> {
+        match tier {
+            CacheTier::Memory => Ok(self.get_from_memory(cache_key)),
+            CacheTier::R2 => {
+                let result = self.get_from_stores(cache_key, policy, encoding).await;
+                if self.index.is_some() && matches!(result, Ok(Some(_))) {
+                    index::record_hit(cache_key);
+                }
+                result
+            }
+        }
+    }
 
-        let mut headers = Headers::new();
-        headers.set("Content-Type", "application/json")?;
+    async fn get_from_stores(
+        &self,
+        cache_key: &str,
+        policy: &MethodPolicy,
+        encoding: ContentEncoding,
+    ) -> Result<Option<Value>> {
+        if let Some(value) = self.get_from_edge(cache_key).await {
+            return Ok(Some(value));
+        }
+        if policy.kv_max_bytes.is_some() {
+            if let Some(value) = self.get_from_kv(cache_key).await {
+                return Ok(Some(value));
+            }
+        }
+        if !self.r2_breaker.allows() {
+            return Err("R2 circuit breaker is open".into());
+        }
+        let result = self.get_from_r2(cache_key, encoding).await;
+        self.r2_breaker.record(&result);
+        result
+    }
 
-        let request = Request::new_with_init(
-            &upstream_url,
-            RequestInit::new()
-                .with_method(Method::Post)
-                .with_headers(headers)
-                .with_body(Some(serde_json::to_string(&rpc_request)?.into())),
-        )?;
+    /// Store a value in the given tier, honoring the method's size limit and memory TTL.
+    /// R2-tier entries are compressed with `encoding` and carry `metadata` in their header;
+    /// its block range is also recorded in storage metadata so entries can be purged by block.
+    /// Entries within the policy's `kv_max_bytes` go to KV instead of R2, and stored entries
+    /// are recorded in the D1 index when it is bound.
+    pub async fn store_in_cache(
+        &self,
+        tier: CacheTier,
+        cache_key: &str,
+        value: &Value,
+        policy: &MethodPolicy,
+        encoding: ContentEncoding,
+        metadata: EntryMetadata,
+    ) -> Result<()> {
+        let value_json = serde_json::to_vec(value)?;
+
+        if let Some(max_bytes) = policy.max_response_bytes {
+            if value_json.len() > max_bytes {
+                console_log!(
+                    "Skipping cache for {}: {} bytes exceeds limit of {}",
+                    cache_key,
+                    value_json.len(),
+                    max_bytes
+                );
+                return Ok(());
+            }
+        }
 
-        let mut response = Fetch::Request(request).send().await?;
-        let response_json: Value = response.json().await?;
+        match tier {
+            CacheTier::Memory => {
+                let ttl_seconds = policy.ttl_seconds.unwrap_or(DEFAULT_MEMORY_TTL_SECONDS);
+                self.store_in_memory(cache_key, value, ttl_seconds);
+                Ok(())
+            }
+            CacheTier::R2 => {
+                let indexed = self.index.as_ref().map(|_| metadata.clone());
+                let result = match self.kv_for(policy, value_json.len()) {
+                    Some(kv) => self
+                        .store_in_kv(kv, cache_key, &value_json, encoding, metadata)
+                        .await
+                        .map(|size| ("kv", size)),
+                    None => {
+                        let result = self.put_in_r2(cache_key, &value_json, encoding, metadata).await;
+                        if result.is_ok() && policy.kv_max_bytes.is_some() {
+                            // An earlier, smaller result in KV would otherwise shadow this one
+                            self.delete_from_kv(cache_key).await;
+                        }
+                        result.map(|size| ("r2", size))
+                    }
+                };
+                let (store, size) = result?;
+
+                self.store_in_edge(cache_key, &value_json);
+                if let Some(metadata) = indexed {
+                    self.index_store(cache_key, metadata, store, size).await;
+                }
+                Ok(())
+            }
+        }
+    }
 
-        if let Some(result) = response_json.get("result").and_then(|v| v.as_str()) {
-            parse_hex_to_u64(result)
-        } else {
-            Err("Failed to get block number".into())
+    /// Store a serialized value in R2 through the circuit breaker. Returns the stored size.
+    async fn put_in_r2(
+        &self,
+        cache_key: &str,
+        value_json: &[u8],
+        encoding: ContentEncoding,
+        metadata: EntryMetadata,
+    ) -> Result<usize> {
+        if !self.r2_breaker.allows() {
+            return Err("R2 circuit breaker is open".into());
         }
+        let result = self.store_in_r2(cache_key, value_json, encoding, metadata).await;
+        self.r2_breaker.record(&result);
+        result
     }
 
-    /// Get logs from R2 cache
-    pub async fn get_logs_from_cache(&self, params: &Value) -> Result<Option<Value>> {
+    /// Record a stored entry in the D1 index, if bound. Failures are logged, not returned.
+    async fn index_store(&self, cache_key: &str, metadata: EntryMetadata, store: &str, size: usize) {
+        if let Some(index) = &self.index {
+            let entry = index_entry(cache_key, metadata, store, size);
+            if let Err(e) = index.record_store(&entry).await {
+                console_log!("ERROR: Failed to index {}: {:?}", cache_key, e);
+            }
+        }
+    }
+
+    /// Get a value from R2 cache. Entries that fail to decode or verify are deleted and
+    /// reported as misses; entries in an older format are queued for rewriting in the current
+    /// one with `encoding`, and
+    /// entries in a newer one are left alone for the release that wrote them.
+    async fn get_from_r2(&self, cache_key: &str, encoding: ContentEncoding) -> Result<Option<Value>> {
         let r2_bucket = match &self.r2_bucket {
             Some(bucket) => bucket,
             None => return Ok(None),
         };
 
-        let cache_key = self.generate_logs_cache_key(params);
+        let object = match r2_bucket.get(cache_key).execute().await? {
+            Some(object) => object,
+            None => return Ok(None),
+        };
+        let r2_metadata = object.custom_metadata()?;
 
-        match r2_bucket.get(&cache_key).execute().await? {
-            Some(object) => {
-                let body = object.body().ok_or("No body in R2 object")?;
+        let verified = match object.body() {
+            Some(body) => {
                 let bytes = body.bytes().await?;
-                let logs: Value = serde_json::from_slice(&bytes)?;
-                Ok(Some(logs))
+                if envelope::is_newer_format(&bytes) {
+                    console_log!("Skipping {}: written in a newer storage format", cache_key);
+                    return Ok(None);
+                }
+                verify_entry(&bytes, r2_metadata.get(CHECKSUM_METADATA).map(String::as_str))
             }
-            None => Ok(None),
+            None => Err("no body".to_string()),
+        };
+        let (entry, value) = match verified {
+            Ok(verified) => verified,
+            Err(reason) => {
+                console_log!("ERROR: Corrupt cache entry {}: {}, deleting", cache_key, reason);
+                metrics::record(Metric::CacheCorrupt, &self.chain_id, key_method(cache_key));
+                if let Err(e) = self.delete_entry(cache_key).await {
+                    console_log!("ERROR: Failed to delete {}: {:?}", cache_key, e);
+                }
+                return Ok(None);
+            }
+        };
+
+        if entry.needs_migration() {
+            let metadata =
+                self.migrated_metadata(cache_key, object.uploaded().as_millis(), &r2_metadata);
+            self.migrations.borrow_mut().push(PendingMigration {
+                cache_key: cache_key.to_string(),
+                value_json: entry.value_json.clone(),
+                encoding,
+                metadata,
+                from_version: entry.version,
+            });
         }
+
+        self.store_in_edge(cache_key, &entry.value_json);
+        Ok(Some(value))
     }
 
-    /// Store logs in R2 cache
-    pub async fn store_logs_in_cache(&self, params: &Value, logs: &Value) -> Result<()> {
-        let r2_bucket = match &self.r2_bucket {
-            Some(bucket) => bucket,
-            None => return Err("R2 bucket not available".into()),
+    /// KV namespace for an entry of `size` bytes, if the policy sends entries that small to KV
+    fn kv_for(&self, policy: &MethodPolicy, size: usize) -> Option<&kv::KvStore> {
+        let max_bytes = policy.kv_max_bytes?;
+        self.kv.as_ref().filter(|_| size <= max_bytes)
+    }
+
+    /// Get a value from KV. Read errors are logged and reported as misses so R2 is still tried;
+    /// entries that fail to verify are deleted.
+    async fn get_from_kv(&self, cache_key: &str) -> Option<Value> {
+        let kv = self.kv.as_ref()?;
+        let read = kv
+            .get(cache_key)
+            .bytes_with_metadata::<HashMap<String, String>>()
+            .await;
+        let (bytes, kv_metadata) = match read {
+            Ok((Some(bytes), kv_metadata)) => (bytes, kv_metadata.unwrap_or_default()),
+            Ok((None, _)) => return None,
+            Err(e) => {
+                console_log!("ERROR: KV read failed for {}: {:?}", cache_key, e);
+                metrics::record(Metric::CacheReadFailure, &self.chain_id, key_method(cache_key));
+                return None;
+            }
         };
 
-        let cache_key = self.generate_logs_cache_key(params);
-        let logs_json = serde_json::to_vec(logs)?;
+        if envelope::is_newer_format(&bytes) {
+            console_log!("Skipping KV entry {}: written in a newer storage format", cache_key);
+            return None;
+        }
+        match verify_entry(&bytes, kv_metadata.get(CHECKSUM_METADATA).map(String::as_str)) {
+            Ok((entry, value)) => {
+                metrics::record(Metric::CacheKvHit, &self.chain_id, key_method(cache_key));
+                self.store_in_edge(cache_key, &entry.value_json);
+                Some(value)
+            }
+            Err(reason) => {
+                console_log!("ERROR: Corrupt KV entry {}: {}, deleting", cache_key, reason);
+                metrics::record(Metric::CacheCorrupt, &self.chain_id, key_method(cache_key));
+                self.delete_from_kv(cache_key).await;
+                None
+            }
+        }
+    }
 
-        r2_bucket.put(&cache_key, logs_json).execute().await?;
+    /// Store a serialized value in KV, wrapped in the same envelope as R2 entries.
+    /// Returns the stored size.
+    async fn store_in_kv(
+        &self,
+        kv: &kv::KvStore,
+        cache_key: &str,
+        value_json: &[u8],
+        encoding: ContentEncoding,
+        metadata: EntryMetadata,
+    ) -> Result<usize> {
+        let header = EntryHeader::new(metadata, value_json);
+        let kv_metadata = storage_metadata(encoding, &header);
+        let stored = envelope::encode(value_json, encoding, &header)?;
+
+        kv.put_bytes(cache_key, &stored)?
+            .metadata(kv_metadata)?
+            .execute()
+            .await?;
 
-        console_log!("Stored logs in R2 cache with key: {}", cache_key);
+        console_log!(
+            "Stored value in KV cache with key: {} ({} bytes as {})",
+            cache_key,
+            stored.len(),
+            encoding.as_str()
+        );
+        Ok(stored.len())
+    }
 
-        Ok(())
+    /// Remove a key from KV, logging failures
+    async fn delete_from_kv(&self, cache_key: &str) {
+        if let Some(kv) = &self.kv {
+            if let Err(e) = kv.delete(cache_key).await {
+                console_log!("ERROR: KV delete failed for {}: {:?}", cache_key, e);
+            }
+        }
     }
 
-    /// Generate cache key for eth_getLogs based on parameters
-    fn generate_logs_cache_key(&self, params: &Value) -> String {
-        // Create a normalized version of the parameters for the cache key
-        let normalized = serde_json::to_string(params).unwrap_or_default();
-        let hash = generate_cache_key(&self.chain_id, &normalized);
-        // Store in eth_getLogs/{chain_id}/ folder
-        format!("eth_getLogs/{}/{}", self.chain_id, hash)
+    /// Look a key up in this colo's edge cache. Errors are logged and treated as misses.
+    async fn get_from_edge(&self, cache_key: &str) -> Option<Value> {
+        let edge = self.edge.as_ref()?;
+        match edge.get(cache_key).await {
+            Ok(Some(value)) => {
+                metrics::record(Metric::CacheEdgeHit, &self.chain_id, key_method(cache_key));
+                Some(value)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                console_log!("ERROR: Edge cache read failed for {}: {:?}", cache_key, e);
+                None
+            }
+        }
     }
 
-    /// Get transaction receipt from R2 cache
-    pub async fn get_tx_receipt_from_cache(&self, tx_hash: &str) -> Result<Option<Value>> {
-        let r2_bucket = match &self.r2_bucket {
-            Some(bucket) => bucket,
-            None => return Ok(None),
-        };
+    /// Queue a copy of an R2 entry for this colo's edge cache, written by `flush_deferred_writes`
+    fn store_in_edge(&self, cache_key: &str, value_json: &[u8]) {
+        if self.edge.is_some() {
+            self.edge_writes
+                .borrow_mut()
+                .push((cache_key.to_string(), value_json.to_vec()));
+        }
+    }
 
-        let cache_key = self.generate_tx_receipt_cache_key(tx_hash);
+    /// Carry out the writes deferred by this request: rewrites of entries read in an older
+    /// format and edge copies. Meant for `ctx.wait_until`, so the writes stay off the request
+    /// path; failures only cost a later migration or R2 read.
+    pub async fn flush_deferred_writes(self) {
+        let migrations = std::mem::take(&mut *self.migrations.borrow_mut());
+        for migration in migrations {
+            self.migrate(migration).await;
+        }
 
-        match r2_bucket.get(&cache_key).execute().await? {
-            Some(object) => {
-                let body = object.body().ok_or("No body in R2 object")?;
-                let bytes = body.bytes().await?;
-                let receipt: Value = serde_json::from_slice(&bytes)?;
-                Ok(Some(receipt))
+        let edge = match self.edge {
+            Some(edge) => edge,
+            None => return,
+        };
+        let writes = std::mem::take(&mut *self.edge_writes.borrow_mut());
+        for (cache_key, value_json) in writes {
+            if let Err(e) = edge.put(&cache_key, &value_json).await {
+                console_log!("ERROR: Edge cache write failed for {}: {:?}", cache_key, e);
             }
-            None => Ok(None),
         }
     }
 
-    /// Store transaction receipt in R2 cache
-    pub async fn store_tx_receipt_in_cache(&self, tx_hash: &str, receipt: &Value) -> Result<()> {
+    /// Rewrite an entry in the current format. The rewrite has a different size, so the index
+    /// is updated too.
+    async fn migrate(&self, migration: PendingMigration) {
+        let PendingMigration {
+            cache_key,
+            value_json,
+            encoding,
+            metadata,
+            from_version,
+        } = migration;
+
+        match self
+            .put_in_r2(&cache_key, &value_json, encoding, metadata.clone())
+            .await
+        {
+            Ok(size) => {
+                console_log!("Migrated {} from format version {}", cache_key, from_version);
+                metrics::record(Metric::CacheMigrated, &self.chain_id, key_method(&cache_key));
+                self.index_store(&cache_key, metadata, "r2", size).await;
+            }
+            Err(e) => console_log!("ERROR: Failed to migrate {}: {:?}", cache_key, e),
+        }
+    }
+
+    /// Header for an entry written before headers existed: what the key and the R2
+    /// metadata tell, with the upload time standing in for the creation time
+    fn migrated_metadata(
+        &self,
+        cache_key: &str,
+        uploaded_ms: u64,
+        r2_metadata: &HashMap<String, String>,
+    ) -> EntryMetadata {
+        EntryMetadata {
+            method: key_method(cache_key).to_string(),
+            chain_id: self.chain.id,
+            created_at_ms: uploaded_ms,
+            block_range: entry_block_range(r2_metadata),
+            ..EntryMetadata::default()
+        }
+    }
+
+    /// Store a serialized value in R2 cache, wrapped in the storage envelope.
+    /// Returns the stored size.
+    async fn store_in_r2(
+        &self,
+        cache_key: &str,
+        value_json: &[u8],
+        encoding: ContentEncoding,
+        metadata: EntryMetadata,
+    ) -> Result<usize> {
         let r2_bucket = match &self.r2_bucket {
             Some(bucket) => bucket,
             None => return Err("R2 bucket not available".into()),
         };
 
-        let cache_key = self.generate_tx_receipt_cache_key(tx_hash);
-        let receipt_json = serde_json::to_vec(receipt)?;
+        let header = EntryHeader::new(metadata, value_json);
+        let r2_metadata = storage_metadata(encoding, &header);
 
-        r2_bucket.put(&cache_key, receipt_json).execute().await?;
+        let stored = envelope::encode(value_json, encoding, &header)?;
+        let stored_len = stored.len();
 
-        console_log!("Stored transaction receipt in R2 cache with key: {}", cache_key);
+        r2_bucket
+            .put(cache_key, stored)
+            .custom_metadata(r2_metadata)
+            .execute()
+            .await?;
 
-        Ok(())
-    }
+        console_log!(
+            "Stored value in R2 cache with key: {} ({} bytes as {}, {} raw)",
+            cache_key,
+            stored_len,
+            encoding.as_str(),
+            value_json.len()
+        );
 
-    /// Generate cache key for eth_getTransactionReceipt
-    fn generate_tx_receipt_cache_key(&self, tx_hash: &str) -> String {
-        // Store in eth_getTransactionReceipt/ folder
-        // Transaction hash is already unique, use it directly (normalized to lowercase)
-        let normalized_hash = tx_hash.to_lowercase();
-        format!("eth_getTransactionReceipt/{}/{}", self.chain_id, normalized_hash)
+        Ok(stored_len)
     }
 
-    /// Check if transaction receipt should be cached
-    /// Receipts are cached if the transaction is confirmed (not null)
-    pub fn should_cache_tx_receipt(&self, receipt: &Value) -> bool {
-        // If receipt is not null and has a blockNumber, it's confirmed
-        if receipt.is_null() {
-            return false;
+    /// Size and metadata of an entry, looking in KV before R2
+    pub async fn inspect_entry(&self, cache_key: &str) -> Result<Option<EntryInfo>> {
+        if let Some(kv) = &self.kv {
+            let (bytes, kv_metadata) = kv
+                .get(cache_key)
+                .bytes_with_metadata::<HashMap<String, String>>()
+                .await?;
+            if let Some(bytes) = bytes {
+                return Ok(Some(EntryInfo {
+                    store: "kv",
+                    size: bytes.len() as u32,
+                    uploaded_ms: None,
+                    metadata: kv_metadata.unwrap_or_default(),
+                    header: envelope::decode_header(&bytes).unwrap_or(None),
+                }));
+            }
         }
-        
-        // Check if receipt has a blockNumber (meaning it's been mined)
-        receipt.get("blockNumber")
-            .and_then(|v| v.as_str())
-            .map(|bn| !bn.is_empty() && bn != "null")
-            .unwrap_or(false)
+
+        self.inspect_r2(cache_key).await
     }
 
-    /// Get block by hash from R2 cache
-    pub async fn get_block_by_hash_from_cache(&self, block_hash: &str) -> Result<Option<Value>> {
+    /// Size and metadata of an R2 entry, if it exists
+    async fn inspect_r2(&self, cache_key: &str) -> Result<Option<EntryInfo>> {
         let r2_bucket = match &self.r2_bucket {
             Some(bucket) => bucket,
             None => return Ok(None),
         };
 
-        let cache_key = self.generate_block_by_hash_cache_key(block_hash);
+        // The header sits at the start of the object, so a short ranged read is enough
+        let object = r2_bucket
+            .get(cache_key)
+            .range(Range::Prefix {
+                length: envelope::HEADER_READ_BYTES as u64,
+            })
+            .execute()
+            .await?;
 
-        match r2_bucket.get(&cache_key).execute().await? {
+        match object {
             Some(object) => {
-                let body = object.body().ok_or("No body in R2 object")?;
-                let bytes = body.bytes().await?;
-                let block: Value = serde_json::from_slice(&bytes)?;
-                Ok(Some(block))
+                let prefix = match object.body() {
+                    Some(body) => body.bytes().await?,
+                    None => Vec::new(),
+                };
+                Ok(Some(EntryInfo {
+                    store: "r2",
+                    size: object.size(),
+                    uploaded_ms: Some(object.uploaded().as_millis()),
+                    metadata: object.custom_metadata()?,
+                    header: envelope::decode_header(&prefix).unwrap_or(None),
+                }))
             }
             None => Ok(None),
         }
     }
 
-    /// Store block by hash in R2 cache
-    pub async fn store_block_by_hash_in_cache(&self, block_hash: &str, block: &Value) -> Result<()> {
-        let r2_bucket = match &self.r2_bucket {
-            Some(bucket) => bucket,
-            None => return Err("R2 bucket not available".into()),
-        };
+    /// Remove an entry from R2, KV, this isolate's memory cache and this colo's edge cache.
+    /// Edge copies in other colos live on until their TTL expires.
+    pub async fn delete_entry(&self, cache_key: &str) -> Result<()> {
+        MEMORY_CACHE.with(|cache| cache.borrow_mut().remove(cache_key));
 
-        let cache_key = self.generate_block_by_hash_cache_key(block_hash);
-        let block_json = serde_json::to_vec(block)?;
-
-        r2_bucket.put(&cache_key, block_json).execute().await?;
+        self.edge_writes.borrow_mut().retain(|(key, _)| key != cache_key);
+        self.migrations.borrow_mut().retain(|migration| migration.cache_key != cache_key);
+        if let Some(edge) = &self.edge {
+            if let Err(e) = edge.delete(cache_key).await {
+                console_log!("ERROR: Edge cache delete failed for {}: {:?}", cache_key, e);
+            }
+        }
 
-        console_log!("Stored block by hash in R2 cache with key: {}", cache_key);
+        if let Some(kv) = &self.kv {
+            kv.delete(cache_key).await?;
+        }
+        if let Some(r2_bucket) = &self.r2_bucket {
+            r2_bucket.delete(cache_key).await?;
+        }
+        self.unindex(&[cache_key.to_string()]).await;
 
+        console_log!("Deleted cache entry {}", cache_key);
         Ok(())
     }
 
-    /// Generate cache key for eth_getBlockByHash
-    fn generate_block_by_hash_cache_key(&self, block_hash: &str) -> String {
-        let normalized_hash = block_hash.to_lowercase();
-        format!("eth_getBlockByHash/{}/{}", self.chain_id, normalized_hash)
+    /// Delete a method's entries, optionally only those overlapping a block range. Block range
+    /// purges are answered from the index when it is bound; otherwise R2 and KV are listed.
+    /// Stops after `max_deletes` so a single call stays within the subrequest limit.
+    pub async fn purge(
+        &self,
+        method: &str,
+        block_range: Option<(u64, u64)>,
+        max_deletes: usize,
+    ) -> Result<PurgeStats> {
+        match (&self.index, block_range) {
+            (Some(index), Some(_)) => {
+                let filter = IndexFilter {
+                    chain_id: self.chain.id,
+                    method: Some(method.to_string()),
+                    block_range,
+                };
+                self.purge_indexed(index, &filter, max_deletes).await
+            }
+            _ => self.purge_prefix(&self.key_prefix(method), block_range, max_deletes).await,
+        }
     }
 
-    /// Check if block should be cached based on block number
-    pub async fn should_cache_block(&self, block: &Value, env: &Env) -> Result<bool> {
-        // Check if block has a number
-        let block_number_str = match block.get("number").and_then(|v| v.as_str()) {
-            Some(bn) => bn,
-            None => return Ok(false),
+    /// Delete every entry of this chain at or above `from_block`, after a reorg replaced those
+    /// blocks. Entries beyond `max_deletes` are tombstoned in the index and deleted by the
+    /// maintenance cron. Needs the index; returns `None` without it.
+    pub async fn invalidate_from_block(
+        &self,
+        from_block: u64,
+        max_deletes: usize,
+    ) -> Result<Option<PurgeStats>> {
+        let index = match &self.index {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let filter = IndexFilter {
+            chain_id: self.chain.id,
+            method: None,
+            block_range: Some((from_block, u64::MAX)),
         };
+        let marked = index.tombstone(&filter, Date::now().as_millis()).await?;
+        let mut stats = self.purge_indexed(index, &filter, max_deletes).await?;
+        stats.tombstoned = marked.saturating_sub(stats.deleted);
+        Ok(Some(stats))
+    }
 
-        // Skip special tags
-        if block_number_str == "latest" || block_number_str == "pending" {
-            return Ok(false);
-        }
+    async fn purge_indexed(
+        &self,
+        index: &CacheIndex,
+        filter: &IndexFilter,
+        max_deletes: usize,
+    ) -> Result<PurgeStats> {
+        let mut stats = PurgeStats {
+            skipped: index.count_without_blocks(filter).await? as usize,
+            ..PurgeStats::default()
+        };
 
-        // Parse block number
-        let block_number = parse_hex_to_u64(block_number_str)?;
+        let keys = index.find_keys(filter, max_deletes + 1).await?;
+        stats.truncated = keys.len() > max_deletes;
+        stats.deleted = self
+            .delete_indexed(keys.into_iter().take(max_deletes).collect())
+            .await?;
 
-        // Get current block number
-        let current_block = match self.get_current_block_number(env).await {
-            Ok(num) => num,
-            Err(e) => {
-                console_log!("Failed to get current block number: {:?}", e);
-                return Ok(false);
+        console_log!("Purged {} indexed entries on chain {}", stats.deleted, self.chain.id);
+        Ok(stats)
+    }
+
+    /// Delete entries found in the index from the backend holding them, then from the index.
+    /// Returns how many were deleted.
+    pub async fn delete_indexed(&self, entries: Vec<IndexedKey>) -> Result<usize> {
+        let mut deleted_keys = Vec::with_capacity(entries.len());
+        let mut result = Ok(());
+        for entry in entries {
+            let deleted = match (entry.store.as_str(), &self.kv, &self.r2_bucket) {
+                ("kv", Some(kv), _) => kv.delete(&entry.key).await.map_err(Error::from),
+                ("r2", _, Some(r2_bucket)) => r2_bucket.delete(&entry.key).await,
+                _ => Ok(()),
+            };
+            if let Err(e) = deleted {
+                result = Err(e);
+                break;
             }
-        };
+            deleted_keys.push(entry.key);
+        }
 
-        let block_distance = self.get_block_distance();
-        
-        // Cache only if block is old enough
-        Ok(block_number + block_distance <= current_block)
+        // Whatever was deleted before a failure must still leave the index
+        self.unindex(&deleted_keys).await;
+        result.map(|()| deleted_keys.len())
     }
 
-    /// Get block receipts from R2 cache
-    pub async fn get_block_receipts_from_cache(&self, block_id: &str) -> Result<Option<Value>> {
-        let r2_bucket = match &self.r2_bucket {
-            Some(bucket) => bucket,
-            None => return Ok(None),
-        };
+    /// Delete R2 and KV entries under a key prefix by listing them
+    async fn purge_prefix(
+        &self,
+        prefix: &str,
+        block_range: Option<(u64, u64)>,
+        max_deletes: usize,
+    ) -> Result<PurgeStats> {
+        let mut stats = PurgeStats::default();
+        self.purge_r2_prefix(prefix, block_range, max_deletes, &mut stats).await?;
+        if !stats.truncated {
+            self.purge_kv_prefix(prefix, block_range, max_deletes, &mut stats).await?;
+        }
+        self.unindex(&stats.deleted_keys).await;
 
-        let cache_key = self.generate_block_receipts_cache_key(block_id);
+        console_log!("Purged {} entries under {}", stats.deleted, prefix);
+        Ok(stats)
+    }
 
-        match r2_bucket.get(&cache_key).execute().await? {
-            Some(object) => {
-                let body = object.body().ok_or("No body in R2 object")?;
-                let bytes = body.bytes().await?;
-                let receipts: Value = serde_json::from_slice(&bytes)?;
-                Ok(Some(receipts))
+    /// Remove deleted keys from the index, logging failures
+    async fn unindex(&self, keys: &[String]) {
+        if let Some(index) = &self.index {
+            if let Err(e) = index.remove(keys).await {
+                console_log!("ERROR: Failed to remove {} keys from the index: {:?}", keys.len(), e);
             }
-            None => Ok(None),
         }
     }
 
-    /// Store block receipts in R2 cache
-    pub async fn store_block_receipts_in_cache(&self, block_id: &str, receipts: &Value) -> Result<()> {
+    async fn purge_r2_prefix(
+        &self,
+        prefix: &str,
+        block_range: Option<(u64, u64)>,
+        max_deletes: usize,
+        stats: &mut PurgeStats,
+    ) -> Result<()> {
         let r2_bucket = match &self.r2_bucket {
             Some(bucket) => bucket,
-            None => return Err("R2 bucket not available".into()),
+            None => return Ok(()),
         };
 
-        let cache_key = self.generate_block_receipts_cache_key(block_id);
-        let receipts_json = serde_json::to_vec(receipts)?;
-
-        r2_bucket.put(&cache_key, receipts_json).execute().await?;
-
-        console_log!("Stored block receipts in R2 cache with key: {}", cache_key);
-
-        Ok(())
-    }
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = r2_bucket
+                .list()
+                .prefix(prefix)
+                .include(vec![Include::CustomMetadata]);
+            if let Some(cursor) = &cursor {
+                list = list.cursor(cursor.clone());
+            }
+            let objects = list.execute().await?;
+
+            for object in objects.objects() {
+                if !stats.selects(&object.custom_metadata()?, block_range) {
+                    continue;
+                }
+                if stats.deleted >= max_deletes {
+                    stats.truncated = true;
+                    return Ok(());
+                }
+                r2_bucket.delete(object.key()).await?;
+                stats.deleted += 1;
+                stats.deleted_keys.push(object.key());
+            }
 
-    /// Generate cache key for eth_getBlockReceipts
-    fn generate_block_receipts_cache_key(&self, block_id: &str) -> String {
-        let normalized = block_id.to_lowercase();
-        format!("eth_getBlockReceipts/{}/{}", self.chain_id, normalized)
+            if !objects.truncated() {
+                return Ok(());
+            }
+            cursor = objects.cursor();
+        }
     }
 
-    /// Get trace from R2 cache
-    pub async fn get_trace_from_cache(&self, method: &str, block_id: &str) -> Result<Option<Value>> {
-        let r2_bucket = match &self.r2_bucket {
-            Some(bucket) => bucket,
-            None => return Ok(None),
+    async fn purge_kv_prefix(
+        &self,
+        prefix: &str,
+        block_range: Option<(u64, u64)>,
+        max_deletes: usize,
+        stats: &mut PurgeStats,
+    ) -> Result<()> {
+        let kv = match &self.kv {
+            Some(kv) => kv,
+            None => return Ok(()),
         };
 
-        let cache_key = self.generate_trace_cache_key(method, block_id);
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = kv.list().prefix(prefix.to_string());
+            if let Some(cursor) = &cursor {
+                list = list.cursor(cursor.clone());
+            }
+            let keys = list.execute().await?;
+
+            for key in keys.keys {
+                let kv_metadata: HashMap<String, String> = key
+                    .metadata
+                    .and_then(|metadata| serde_json::from_value(metadata).ok())
+                    .unwrap_or_default();
+                if !stats.selects(&kv_metadata, block_range) {
+                    continue;
+                }
+                if stats.deleted >= max_deletes {
+                    stats.truncated = true;
+                    return Ok(());
+                }
+                kv.delete(&key.name).await?;
+                stats.deleted += 1;
+                stats.deleted_keys.push(key.name);
+            }
 
-        match r2_bucket.get(&cache_key).execute().await? {
-            Some(object) => {
-                let body = object.body().ok_or("No body in R2 object")?;
-                let bytes = body.bytes().await?;
-                let trace: Value = serde_json::from_slice(&bytes)?;
-                Ok(Some(trace))
+            if keys.list_complete {
+                return Ok(());
             }
-            None => Ok(None),
+            cursor = keys.cursor;
         }
     }
 
-    /// Store trace in R2 cache
-    pub async fn store_trace_in_cache(&self, method: &str, block_id: &str, trace: &Value) -> Result<()> {
-        let r2_bucket = match &self.r2_bucket {
-            Some(bucket) => bucket,
-            None => return Err("R2 bucket not available".into()),
-        };
+    /// Prefix shared by all R2 keys of a method on this chain
+    pub fn key_prefix(&self, method: &str) -> String {
+        format!("{}/{}/", method, self.chain_id)
+    }
 
-        let cache_key = self.generate_trace_cache_key(method, block_id);
-        let trace_json = serde_json::to_vec(trace)?;
+    /// Generate cache key for eth_getLogs based on parameters
+    pub fn generate_logs_cache_key(&self, params: &Value) -> String {
+        // Create a normalized version of the parameters for the cache key
+        let normalized = serde_json::to_string(params).unwrap_or_default();
+        let hash = generate_cache_key(&self.chain_id, &normalized);
+        // Store in eth_getLogs/{chain_id}/ folder
+        format!("{}{}", self.key_prefix("eth_getLogs"), hash)
+    }
 
-        r2_bucket.put(&cache_key, trace_json).execute().await?;
+    /// Generate cache key for eth_getTransactionReceipt
+    pub fn generate_tx_receipt_cache_key(&self, tx_hash: &str) -> String {
+        // Store in eth_getTransactionReceipt/ folder
+        // Transaction hash is already unique, use it directly (normalized to lowercase)
+        let normalized_hash = tx_hash.to_lowercase();
+        format!("{}{}", self.key_prefix("eth_getTransactionReceipt"), normalized_hash)
+    }
 
-        console_log!("Stored trace in R2 cache with key: {}", cache_key);
+    /// Generate cache key for eth_getBlockByHash
+    pub fn generate_block_by_hash_cache_key(&self, block_hash: &str) -> String {
+        let normalized_hash = block_hash.to_lowercase();
+        format!("{}{}", self.key_prefix("eth_getBlockByHash"), normalized_hash)
+    }
 
-        Ok(())
+    /// Generate cache key for eth_getBlockReceipts
+    pub fn generate_block_receipts_cache_key(&self, block_id: &str) -> String {
+        let normalized = block_id.to_lowercase();
+        format!("{}{}", self.key_prefix("eth_getBlockReceipts"), normalized)
     }
 
     /// Generate cache key for debug trace methods
-    fn generate_trace_cache_key(&self, method: &str, block_id: &str) -> String {
+    pub fn generate_trace_cache_key(&self, method: &str, block_id: &str) -> String {
         let normalized = block_id.to_lowercase();
-        format!("{}/{}/{}", method, self.chain_id, normalized)
+        format!("{}{}", self.key_prefix(method), normalized)
     }
 
-    /// Check if block ID should be cached (for block receipts and traces)
-    /// For block numbers, we can check directly. For block hashes, caller should extract
-    /// block number from response and use should_cache_by_block_number instead.
-    pub async fn should_cache_block_id(&self, block_id: &str, env: &Env) -> Result<bool> {
-        // Skip special tags
-        if block_id == "latest" || block_id == "pending" || block_id == "earliest" {
-            return Ok(false);
-        }
+    /// Generate in-memory cache key for eth_getBlockByNumber
+    /// Full and hash-only blocks are different responses, so the flag is part of the key
+    pub fn generate_block_by_number_cache_key(&self, block_number: &str, full_transactions: bool) -> String {
+        format!("{}:{}:{}", self.chain_id, block_number, full_transactions)
+    }
 
-        // If it's a block hash (0x followed by 64 hex chars), we can't determine without response
-        // Caller should extract block number from response and check
-        if block_id.starts_with("0x") && block_id.len() == 66 {
-            // Return Ok(false) to indicate: need to check response
-            // This is just a signal - not that it's uncacheable, but that we need response data
-            console_log!("Block hash detected - will check block number from response");
-            return Ok(false);
-        }
+    /// Get value from in-memory cache, dropping it once its TTL has passed
+    pub fn get_from_memory(&self, cache_key: &str) -> Option<Value> {
+        let now = Date::now().as_millis();
+
+        MEMORY_CACHE.with(|cache| {
+            let mut cache = cache.borrow_mut();
+
+            if let Some(cached) = cache.get(cache_key) {
+                let age_ms = now.saturating_sub(cached.timestamp_ms);
+                if age_ms < cached.ttl_ms {
+                    console_log!("Memory cache HIT for {} (age: {:.2}s)", cache_key, age_ms as f64 / 1000.0);
+                    return Some(cached.data.clone());
+                } else {
+                    console_log!("Memory cache EXPIRED for {} (age: {:.2}s)", cache_key, age_ms as f64 / 1000.0);
+                    // Remove expired entry
+                    cache.remove(cache_key);
+                }
+            }
 
-        // It's a block number - parse and check distance
-        let block_number = parse_hex_to_u64(block_id)?;
-        self.should_cache_by_block_number(block_number, env).await
+            None
+        })
     }
 
-    /// Check if a specific block number should be cached
-    pub async fn should_cache_by_block_number(&self, block_number: u64, env: &Env) -> Result<bool> {
-        // Get current block number
-        let current_block = match self.get_current_block_number(env).await {
-            Ok(num) => num,
-            Err(e) => {
-                console_log!("Failed to get current block number: {:?}", e);
-                return Ok(false);
-            }
+    /// Store value in in-memory cache with timestamp and TTL
+    pub fn store_in_memory(&self, cache_key: &str, value: &Value, ttl_seconds: u64) {
+        let now = Date::now().as_millis();
+
+        let entry = CachedEntry {
+            data: value.clone(),
+            timestamp_ms: now,
+            ttl_ms: ttl_seconds * 1000,
         };
 
-        let block_distance = self.get_block_distance();
-        
-        // Cache only if block is old enough
-        let should_cache = block_number + block_distance <= current_block;
-        
-        console_log!(
-            "Block number {} check: current={}, distance={}, should_cache={}",
-            block_number, current_block, block_distance, should_cache
-        );
-        
-        Ok(should_cache)
+        MEMORY_CACHE.with(|cache| cache.borrow_mut().insert(cache_key.to_string(), entry));
+
+        console_log!("Stored {} in memory cache with {}s TTL", cache_key, ttl_seconds);
+
+        // Clean up expired entries to prevent memory bloat
+        Self::cleanup_expired_cache(now);
     }
 
-    /// Extract block number from response data and check if cacheable
-    pub async fn should_cache_from_response(&self, response_data: &Value, env: &Env) -> Result<bool> {
-        // Try to extract block number from response
-        // Could be at different paths depending on response type
-        let block_number_str = if let Some(bn) = response_data.get("blockNumber").and_then(|v| v.as_str()) {
-            // For receipts, traces might have blockNumber
-            bn
-        } else if let Some(bn) = response_data.get("number").and_then(|v| v.as_str()) {
-            // For blocks, use number field
-            bn
-        } else {
-            console_log!("No block number found in response");
-            return Ok(false);
-        };
+    /// Clean up expired cache entries
+    fn cleanup_expired_cache(now: u64) {
+        MEMORY_CACHE.with(|cache| {
+            cache
+                .borrow_mut()
+                .retain(|_, cached| now.saturating_sub(cached.timestamp_ms) < cached.ttl_ms)
+        });
+    }
+}
 
-        // Skip if null or special tags
-        if block_number_str == "null" || block_number_str.is_empty() {
-            return Ok(false);
-        }
+/// Blocks data must be behind the tip to be cached. Immediate finality drops the distance,
+/// but the block must still exist: results for blocks past the tip are empty or partial.
+fn required_block_distance(policy: &MethodPolicy, chain_distance: u64) -> u64 {
+    match policy.finality {
+        FinalityMode::Immediate => 0,
+        FinalityMode::Distance => policy.block_distance.unwrap_or(chain_distance),
+    }
+}
 
-        // Parse and check
-        let block_number = parse_hex_to_u64(block_number_str)?;
-        self.should_cache_by_block_number(block_number, env).await
+/// Index row for an entry just written to `store`
+fn index_entry(cache_key: &str, metadata: EntryMetadata, store: &str, size: usize) -> IndexEntry {
+    IndexEntry {
+        key: cache_key.to_string(),
+        method: metadata.method,
+        chain_id: metadata.chain_id,
+        from_block: metadata.block_range.map(|(from_block, _)| from_block),
+        to_block: metadata.block_range.map(|(_, to_block)| to_block),
+        block_hash: metadata.block_hash,
+        store: store.to_string(),
+        size: size as u64,
+        created_at_ms: metadata.created_at_ms,
+        hits: 0,
+        last_hit_ms: None,
     }
+}
 
-    /// Get block from in-memory cache (2-second TTL)
-    pub fn get_block_from_cache(&self, block_number: &str) -> Option<Value> {
-        let now = Date::now().as_millis();
-        let cache_key = format!("{}:{}", self.chain_id, block_number);
-        
-        let mut cache = self.block_cache.borrow_mut();
-        
-        if let Some(cached) = cache.get(&cache_key) {
-            // Check if cache entry is still valid (within 2 seconds)
-            if now - cached.timestamp_ms < 2000 {
-                console_log!("Block cache HIT for {} (age: {:.2}s)", block_number, (now - cached.timestamp_ms) as f64 / 1000.0);
-                return Some(cached.data.clone());
-            } else {
-                console_log!("Block cache EXPIRED for {} (age: {:.2}s)", block_number, (now - cached.timestamp_ms) as f64 / 1000.0);
-                // Remove expired entry
-                cache.remove(&cache_key);
+/// Storage metadata written next to an entry in R2 or KV
+fn storage_metadata(encoding: ContentEncoding, header: &EntryHeader) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+    metadata.insert(ENCODING_METADATA.to_string(), encoding.as_str().to_string());
+    metadata.insert(CHECKSUM_METADATA.to_string(), header.checksum.clone());
+    if let Some((from_block, to_block)) = header.metadata.block_range {
+        metadata.insert(FROM_BLOCK_METADATA.to_string(), from_block.to_string());
+        metadata.insert(TO_BLOCK_METADATA.to_string(), to_block.to_string());
+    }
+    metadata
+}
+
+/// Unwrap a stored object and check it against the checksum kept in its R2 metadata and
+/// in its header. Entries written before checksums existed can only be checked for valid JSON.
+fn verify_entry(
+    bytes: &[u8],
+    stored_checksum: Option<&str>,
+) -> std::result::Result<(DecodedEntry, Value), String> {
+    let entry = envelope::decode(bytes).map_err(|e| e.to_string())?;
+
+    let header_checksum = entry.header.as_ref().map(|header| header.checksum.as_str());
+    if stored_checksum.is_some() || header_checksum.is_some() {
+        let actual = envelope::checksum(&entry.value_json);
+        for expected in stored_checksum.into_iter().chain(header_checksum) {
+            if expected != actual {
+                return Err(format!("checksum mismatch: expected {expected}, got {actual}"));
             }
         }
-        
-        None
     }
 
-    /// Store block in in-memory cache with timestamp
-    pub fn store_block_in_cache(&self, block_number: &str, block: &Value) {
-        let now = Date::now().as_millis();
-        let cache_key = format!("{}:{}", self.chain_id, block_number);
-        
-        let cached_block = CachedBlock {
-            data: block.clone(),
-            timestamp_ms: now,
-        };
-        
-        self.block_cache.borrow_mut().insert(cache_key.clone(), cached_block);
-        
-        console_log!("Stored block {} in memory cache with 2s TTL", block_number);
-        
-        // Optional: Clean up expired entries to prevent memory bloat
-        self.cleanup_expired_cache();
-    }
+    let value = serde_json::from_slice(&entry.value_json)
+        .map_err(|e| format!("invalid JSON payload: {e}"))?;
+    Ok((entry, value))
+}
 
-    /// Clean up expired cache entries
-    fn cleanup_expired_cache(&self) {
-        let now = Date::now().as_millis();
-        let mut cache = self.block_cache.borrow_mut();
-        
-        cache.retain(|_, cached| now - cached.timestamp_ms < 2000);
-    }
+/// Method an entry belongs to, from the first segment of its key
+fn key_method(cache_key: &str) -> &str {
+    cache_key.split('/').next().unwrap_or(cache_key)
+}
+
+/// Blocks covered by an entry, as recorded in its storage metadata
+fn entry_block_range(metadata: &HashMap<String, String>) -> Option<(u64, u64)> {
+    let from_block = metadata.get(FROM_BLOCK_METADATA)?.parse().ok()?;
+    let to_block = metadata.get(TO_BLOCK_METADATA)?.parse().ok()?;
+    Some((from_block, to_block))
+}
+
+fn ranges_overlap(a: (u64, u64), b: (u64, u64)) -> bool {
+    a.0 <= b.1 && b.0 <= a.1
 }
 
 #[cfg(test)]
@@ -597,6 +1187,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_immediate_finality_needs_mined_block() {
+        let immediate = MethodPolicy {
+            finality: FinalityMode::Immediate,
+            ..MethodPolicy::default()
+        };
+        let distance = required_block_distance(&immediate, 100);
+        assert_eq!(distance, 0);
+        assert!(1000 + distance <= 1000);
+        assert!(1001 + distance > 1000);
+
+        let overridden = MethodPolicy {
+            block_distance: Some(5),
+            ..MethodPolicy::default()
+        };
+        assert_eq!(required_block_distance(&overridden, 100), 5);
+        assert_eq!(required_block_distance(&MethodPolicy::default(), 100), 100);
+    }
+
     #[test]
     fn test_special_block_tags_not_cacheable() {
         // Special tags should never be cacheable
@@ -966,5 +1575,95 @@ mod tests {
         assert_ne!(key2, key3);
         assert_ne!(key1, key3);
     }
-}
 
+    #[test]
+    fn test_verify_entry() {
+        let json = br#"{"blockNumber":"0x1"}"#;
+        let header = EntryHeader::new(EntryMetadata::default(), json);
+        let stored = envelope::encode(json, ContentEncoding::Gzip, &header).unwrap();
+
+        let (entry, value) = verify_entry(&stored, Some(&header.checksum)).unwrap();
+        assert_eq!(entry.value_json, json);
+        assert_eq!(value["blockNumber"], "0x1");
+
+        // Header checksum alone is enough
+        assert!(verify_entry(&stored, None).is_ok());
+        // A checksum in R2 metadata that doesn't match the payload
+        assert!(verify_entry(&stored, Some("00")).unwrap_err().contains("checksum mismatch"));
+
+        // Damaged payload or header
+        let mut damaged = stored.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xff;
+        assert!(verify_entry(&damaged, None).is_err());
+        assert!(verify_entry(&stored[..12], None).is_err());
+    }
+
+    #[test]
+    fn test_verify_legacy_entry() {
+        let json = br#"{"blockNumber":"0x1"}"#;
+        assert!(verify_entry(json, None).is_ok());
+        assert!(verify_entry(&json[..10], None).unwrap_err().contains("invalid JSON"));
+
+        // A bare entry that does match a stored checksum
+        let checksum = envelope::checksum(json);
+        assert!(verify_entry(json, Some(&checksum)).is_ok());
+    }
+
+    #[test]
+    fn test_key_method() {
+        assert_eq!(key_method("eth_getLogs/1/abc"), "eth_getLogs");
+        assert_eq!(key_method("legacy-key"), "legacy-key");
+    }
+
+    #[test]
+    fn test_entry_block_range_from_metadata() {
+        let mut metadata = HashMap::new();
+        assert_eq!(entry_block_range(&metadata), None);
+
+        metadata.insert(FROM_BLOCK_METADATA.to_string(), "100".to_string());
+        metadata.insert(TO_BLOCK_METADATA.to_string(), "200".to_string());
+        assert_eq!(entry_block_range(&metadata), Some((100, 200)));
+
+        metadata.insert(TO_BLOCK_METADATA.to_string(), "0xc8".to_string());
+        assert_eq!(entry_block_range(&metadata), None);
+    }
+
+    #[test]
+    fn test_block_ranges_overlap() {
+        assert!(ranges_overlap((100, 200), (150, 150)));
+        assert!(ranges_overlap((100, 200), (200, 300)));
+        assert!(ranges_overlap((150, 150), (100, 200)));
+        assert!(!ranges_overlap((100, 200), (201, 300)));
+        assert!(!ranges_overlap((300, 400), (100, 200)));
+    }
+
+    #[test]
+    fn test_storage_metadata() {
+        let metadata = EntryMetadata {
+            block_range: Some((100, 200)),
+            ..EntryMetadata::default()
+        };
+        let header = EntryHeader::new(metadata, b"{}");
+        let stored = storage_metadata(ContentEncoding::Zstd, &header);
+
+        assert_eq!(stored[ENCODING_METADATA], "zstd");
+        assert_eq!(stored[CHECKSUM_METADATA], header.checksum);
+        assert_eq!(entry_block_range(&stored), Some((100, 200)));
+    }
+
+    #[test]
+    fn test_purge_selection() {
+        let mut stats = PurgeStats::default();
+        let mut metadata = HashMap::new();
+        assert!(stats.selects(&metadata, None));
+        assert!(!stats.selects(&metadata, Some((0, 10))));
+        assert_eq!(stats.skipped, 1);
+
+        metadata.insert(FROM_BLOCK_METADATA.to_string(), "5".to_string());
+        metadata.insert(TO_BLOCK_METADATA.to_string(), "8".to_string());
+        assert!(stats.selects(&metadata, Some((0, 5))));
+        assert!(!stats.selects(&metadata, Some((9, 10))));
+        assert_eq!(stats.skipped, 1);
+    }
+}
@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use worker::*;
+
+use crate::cache::CacheTier;
+use crate::envelope::ContentEncoding;
+
+/// Method name that applies a policy to every cached method
+pub const ALL_METHODS: &str = "*";
+
+/// TTL used for memory tier entries when the policy does not set one
+pub const DEFAULT_MEMORY_TTL_SECONDS: u64 = 2;
+
+/// How a method decides that data is final
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinalityMode {
+    /// Cache once the block is `block_distance` blocks behind the tip
+    Distance,
+    /// Cache as soon as the data references a mined block (instant-finality chains)
+    Immediate,
+}
+
+/// Policy overrides for one method. Every field is optional so layers can be merged.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MethodPolicyConfig {
+    pub enabled: Option<bool>,
+    pub finality: Option<FinalityMode>,
+    pub block_distance: Option<u64>,
+    /// Keep non-final data in the memory tier for this many seconds
+    pub ttl_seconds: Option<u64>,
+    pub max_response_bytes: Option<usize>,
+    pub tier: Option<CacheTier>,
+    /// Compression of R2 entries
+    pub encoding: Option<ContentEncoding>,
+    /// Keep entries up to this many bytes in KV instead of R2
+    pub kv_max_bytes: Option<usize>,
+}
+
+impl MethodPolicyConfig {
+    fn apply_to(&self, policy: &mut MethodPolicy) {
+        if let Some(enabled) = self.enabled {
+            policy.enabled = enabled;
+        }
+        if let Some(finality) = self.finality {
+            policy.finality = finality;
+        }
+        if self.block_distance.is_some() {
+            policy.block_distance = self.block_distance;
+        }
+        if self.ttl_seconds.is_some() {
+            policy.ttl_seconds = self.ttl_seconds;
+        }
+        if self.max_response_bytes.is_some() {
+            policy.max_response_bytes = self.max_response_bytes;
+        }
+        if self.tier.is_some() {
+            policy.tier = self.tier;
+        }
+        if self.encoding.is_some() {
+            policy.encoding = self.encoding;
+        }
+        if self.kv_max_bytes.is_some() {
+            policy.kv_max_bytes = self.kv_max_bytes;
+        }
+    }
+}
+
+/// Caching policy loaded from the `CACHE_POLICY` variable:
+///
+/// ```json
+/// {
+///   "defaults": { "*": { "max_response_bytes": 10000000 } },
+///   "chains": { "137": { "eth_getLogs": { "block_distance": 200 } } }
+/// }
+/// ```
+///
+/// Layers are applied in order: built-in, `defaults["*"]`, `defaults[method]`,
+/// `chains[chain]["*"]`, `chains[chain][method]`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CachePolicyConfig {
+    #[serde(default)]
+    pub defaults: HashMap<String, MethodPolicyConfig>,
+    #[serde(default)]
+    pub chains: HashMap<String, HashMap<String, MethodPolicyConfig>>,
+}
+
+/// Effective policy for one method on one chain
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MethodPolicy {
+    pub enabled: bool,
+    pub finality: FinalityMode,
+    /// Overrides the chain's block distance when set
+    pub block_distance: Option<u64>,
+    pub ttl_seconds: Option<u64>,
+    pub max_response_bytes: Option<usize>,
+    /// Overrides the handler's storage tier when set
+    pub tier: Option<CacheTier>,
+    /// Overrides the handler's R2 compression when set
+    pub encoding: Option<ContentEncoding>,
+    /// Size hint: entries of this method are usually small, so those up to this size are
+    /// stored in KV and lookups try KV before R2
+    pub kv_max_bytes: Option<usize>,
+}
+
+impl Default for MethodPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            finality: FinalityMode::Distance,
+            block_distance: None,
+            ttl_seconds: None,
+            max_response_bytes: None,
+            tier: None,
+            encoding: None,
+            kv_max_bytes: None,
+        }
+    }
+}
+
+impl CachePolicyConfig {
+    /// Load and validate the policy from `CACHE_POLICY`; an unset variable means built-in defaults
+    pub fn from_env(env: &Env, known_methods: &[&str]) -> Result<Self> {
+        let raw = match env.var("CACHE_POLICY") {
+            Ok(var) => var.to_string(),
+            Err(_) => return Ok(Self::default()),
+        };
+
+        let config = Self::parse(&raw)?;
+        config.validate(known_methods)?;
+        Ok(config)
+    }
+
+    pub fn parse(raw: &str) -> Result<Self> {
+        if raw.trim().is_empty() {
+            return Ok(Self::default());
+        }
+
+        serde_json::from_str(raw)
+            .map_err(|e| Error::RustError(format!("Invalid CACHE_POLICY: {e}")))
+    }
+
+    /// Check the policy for mistakes, reporting all of them at once
+    pub fn validate(&self, known_methods: &[&str]) -> Result<()> {
+        let mut errors = Vec::new();
+
+        validate_methods("defaults", &self.defaults, known_methods, &mut errors);
+        for (chain_id, methods) in &self.chains {
+            if chain_id.parse::<u64>().is_err() {
+                errors.push(format!("chains.{chain_id}: chain id must be a decimal number"));
+            }
+            validate_methods(&format!("chains.{chain_id}"), methods, known_methods, &mut errors);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            errors.sort();
+            Err(Error::RustError(format!(
+                "Invalid CACHE_POLICY: {}",
+                errors.join("; ")
+            )))
+        }
+    }
+
+    /// Resolve the effective policy for a method on a chain, starting from the chain's base policy
+    pub fn resolve(&self, base: MethodPolicy, chain_id: &str, method: &str) -> MethodPolicy {
+        let mut policy = base;
+
+        let chain = self.chains.get(chain_id);
+        let layers = [
+            self.defaults.get(ALL_METHODS),
+            self.defaults.get(method),
+            chain.and_then(|methods| methods.get(ALL_METHODS)),
+            chain.and_then(|methods| methods.get(method)),
+        ];
+
+        for layer in layers.into_iter().flatten() {
+            layer.apply_to(&mut policy);
+        }
+
+        policy
+    }
+}
+
+fn validate_methods(
+    scope: &str,
+    methods: &HashMap<String, MethodPolicyConfig>,
+    known_methods: &[&str],
+    errors: &mut Vec<String>,
+) {
+    for (method, policy) in methods {
+        let location = format!("{scope}.{method}");
+
+        if method != ALL_METHODS && !known_methods.contains(&method.as_str()) {
+            errors.push(format!("{location}: unknown cached method"));
+        }
+        if policy.ttl_seconds == Some(0) {
+            errors.push(format!("{location}: ttl_seconds must be greater than 0"));
+        }
+        if policy.max_response_bytes == Some(0) {
+            errors.push(format!("{location}: max_response_bytes must be greater than 0"));
+        }
+        if policy.kv_max_bytes == Some(0) {
+            errors.push(format!("{location}: kv_max_bytes must be greater than 0"));
+        }
+        if policy.finality == Some(FinalityMode::Immediate) && policy.block_distance.is_some() {
+            errors.push(format!(
+                "{location}: block_distance has no effect with finality \"immediate\""
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHODS: &[&str] = &["eth_getLogs", "eth_getBlockByNumber"];
+
+    #[test]
+    fn test_empty_policy_uses_defaults() {
+        let config = CachePolicyConfig::parse("").unwrap();
+        assert_eq!(config.resolve(MethodPolicy::default(), "1", "eth_getLogs"), MethodPolicy::default());
+    }
+
+    #[test]
+    fn test_policy_layering() {
+        let config = CachePolicyConfig::parse(
+            r#"{
+                "defaults": {
+                    "*": {"max_response_bytes": 1000},
+                    "eth_getLogs": {"block_distance": 50},
+                    "eth_getBlockByNumber": {"kv_max_bytes": 512}
+                },
+                "chains": {
+                    "137": {
+                        "*": {"ttl_seconds": 5},
+                        "eth_getLogs": {"block_distance": 200, "tier": "memory", "encoding": "zstd"}
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        config.validate(METHODS).unwrap();
+
+        let mainnet = config.resolve(MethodPolicy::default(), "1", "eth_getLogs");
+        assert_eq!(mainnet.block_distance, Some(50));
+        assert_eq!(mainnet.max_response_bytes, Some(1000));
+        assert_eq!(mainnet.ttl_seconds, None);
+
+        let polygon = config.resolve(MethodPolicy::default(), "137", "eth_getLogs");
+        assert_eq!(polygon.block_distance, Some(200));
+        assert_eq!(polygon.ttl_seconds, Some(5));
+        assert_eq!(polygon.tier, Some(CacheTier::Memory));
+        assert_eq!(polygon.encoding, Some(ContentEncoding::Zstd));
+        assert_eq!(polygon.max_response_bytes, Some(1000));
+
+        let other = config.resolve(MethodPolicy::default(), "137", "eth_getBlockByNumber");
+        assert_eq!(other.block_distance, None);
+        assert_eq!(other.kv_max_bytes, Some(512));
+        assert_eq!(polygon.kv_max_bytes, None);
+        assert!(other.enabled);
+    }
+
+    #[test]
+    fn test_unknown_fields_rejected() {
+        let err = CachePolicyConfig::parse(r#"{"defaults": {"eth_getLogs": {"distance": 5}}}"#);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_validation_reports_all_errors() {
+        let config = CachePolicyConfig::parse(
+            r#"{
+                "defaults": {"eth_foo": {}},
+                "chains": {
+                    "polygon": {"eth_getLogs": {"ttl_seconds": 0, "kv_max_bytes": 0}},
+                    "1": {"eth_getLogs": {"finality": "immediate", "block_distance": 5}}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let message = config.validate(METHODS).unwrap_err().to_string();
+        assert!(message.contains("defaults.eth_foo: unknown cached method"));
+        assert!(message.contains("chains.polygon: chain id must be a decimal number"));
+        assert!(message.contains("chains.polygon.eth_getLogs: ttl_seconds must be greater than 0"));
+        assert!(message.contains("chains.polygon.eth_getLogs: kv_max_bytes must be greater than 0"));
+        assert!(message.contains("chains.1.eth_getLogs: block_distance has no effect"));
+    }
+}
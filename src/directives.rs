@@ -0,0 +1,109 @@
+use worker::*;
+
+/// Request header carrying cache directives, e.g. `X-Cache-Control: no-cache, no-store`
+pub const DIRECTIVES_HEADER: &str = "X-Cache-Control";
+/// Query parameter alternative, e.g. `?cache=refresh`
+pub const DIRECTIVES_QUERY_PARAM: &str = "cache";
+
+/// Client-requested changes to the caching pipeline
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheDirectives {
+    /// Skip the cache lookup, but store the fresh result as usual
+    pub no_cache: bool,
+    /// Do not write the result to the cache
+    pub no_store: bool,
+    /// Skip the lookup and replace the stored entry; if the fresh result is not cacheable
+    /// any existing entry is deleted
+    pub refresh: bool,
+    /// Answer from the cache only; misses are rejected instead of going upstream
+    pub only_if_cached: bool,
+}
+
+impl CacheDirectives {
+    /// Parse a comma separated directive list; unknown directives are ignored
+    pub fn parse(value: &str) -> Self {
+        let mut directives = Self::default();
+        for directive in value.split(',') {
+            match directive.trim().to_ascii_lowercase().as_str() {
+                "no-cache" => directives.no_cache = true,
+                "no-store" => directives.no_store = true,
+                "refresh" => directives.refresh = true,
+                "only-if-cached" => directives.only_if_cached = true,
+                _ => {}
+            }
+        }
+        directives
+    }
+
+    /// Directives from the request header and query parameter, combined
+    pub fn from_request(req: &Request) -> Result<Self> {
+        let mut directives = Self::default();
+
+        if let Some(header) = req.headers().get(DIRECTIVES_HEADER)? {
+            directives = directives.union(Self::parse(&header));
+        }
+        for (name, value) in req.url()?.query_pairs() {
+            if name == DIRECTIVES_QUERY_PARAM {
+                directives = directives.union(Self::parse(&value));
+            }
+        }
+
+        Ok(directives)
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            no_cache: self.no_cache || other.no_cache,
+            no_store: self.no_store || other.no_store,
+            refresh: self.refresh || other.refresh,
+            only_if_cached: self.only_if_cached || other.only_if_cached,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether the cache lookup is skipped
+    pub fn skips_read(&self) -> bool {
+        self.no_cache || self.refresh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_directives() {
+        assert!(CacheDirectives::parse("").is_empty());
+        assert!(CacheDirectives::parse("max-age=0").is_empty());
+
+        let directives = CacheDirectives::parse("No-Cache, no-store");
+        assert!(directives.no_cache);
+        assert!(directives.no_store);
+        assert!(!directives.refresh);
+        assert!(directives.skips_read());
+
+        let refresh = CacheDirectives::parse("refresh");
+        assert!(refresh.skips_read());
+        assert!(!refresh.no_store);
+
+        assert!(!CacheDirectives::parse("no-store").skips_read());
+        assert!(CacheDirectives::parse("only-if-cached").only_if_cached);
+    }
+
+    #[test]
+    fn test_union() {
+        let combined = CacheDirectives::parse("no-store").union(CacheDirectives::parse("refresh"));
+        assert_eq!(
+            combined,
+            CacheDirectives {
+                no_cache: false,
+                no_store: true,
+                refresh: true,
+                only_if_cached: false,
+            }
+        );
+    }
+}
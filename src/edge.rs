@@ -0,0 +1,99 @@
+use serde_json::Value;
+use worker::*;
+
+/// Origin of the synthetic URLs edge entries are stored under; it is never fetched
+const EDGE_CACHE_ORIGIN: &str = "https://rpc-cache.internal/";
+
+/// How long edge copies live when `EDGE_CACHE_TTL_SECONDS` is not set. Kept short because
+/// purges and reorg invalidations cannot reach the copies held by other colos.
+pub const DEFAULT_EDGE_TTL_SECONDS: u64 = 5 * 60;
+
+/// Per-colo copy of R2 entries in the Workers Cache API, keyed by the same cache keys.
+/// Entries expire after the TTL; deletes only reach the colo that handles them, so a purged
+/// entry may be served elsewhere until its copy expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdgeCache {
+    ttl_seconds: u64,
+}
+
+impl EdgeCache {
+    /// Load from `EDGE_CACHE_TTL_SECONDS`; `0` disables the edge tier
+    pub fn from_env(env: &Env) -> Result<Option<Self>> {
+        let raw = env.var("EDGE_CACHE_TTL_SECONDS").ok().map(|v| v.to_string());
+        Self::parse(raw.as_deref())
+    }
+
+    fn parse(raw: Option<&str>) -> Result<Option<Self>> {
+        let ttl_seconds = match raw.map(str::trim).filter(|raw| !raw.is_empty()) {
+            Some(raw) => raw.parse::<u64>().map_err(|e| {
+                Error::RustError(format!("Invalid EDGE_CACHE_TTL_SECONDS \"{raw}\": {e}"))
+            })?,
+            None => DEFAULT_EDGE_TTL_SECONDS,
+        };
+
+        Ok((ttl_seconds > 0).then_some(Self { ttl_seconds }))
+    }
+
+    fn url(cache_key: &str) -> String {
+        format!("{EDGE_CACHE_ORIGIN}{cache_key}")
+    }
+
+    /// Look an entry up in this colo. Unreadable copies are dropped and reported as misses.
+    pub async fn get(&self, cache_key: &str) -> Result<Option<Value>> {
+        let url = Self::url(cache_key);
+        let mut response = match Cache::default().get(&url, false).await? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+
+        let bytes = response.bytes().await?;
+        match serde_json::from_slice(&bytes) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => {
+                console_log!("ERROR: Invalid edge cache entry {}: {}, deleting", cache_key, e);
+                self.delete(cache_key).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Store serialized JSON in this colo
+    pub async fn put(&self, cache_key: &str, value_json: &[u8]) -> Result<()> {
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "application/json")?;
+        headers.set("Cache-Control", &format!("max-age={}", self.ttl_seconds))?;
+
+        let response = Response::from_bytes(value_json.to_vec())?.with_headers(headers);
+        Cache::default().put(Self::url(cache_key), response).await
+    }
+
+    /// Remove an entry from this colo
+    pub async fn delete(&self, cache_key: &str) -> Result<()> {
+        Cache::default().delete(Self::url(cache_key), false).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(
+            EdgeCache::parse(None).unwrap(),
+            Some(EdgeCache { ttl_seconds: DEFAULT_EDGE_TTL_SECONDS })
+        );
+        assert_eq!(EdgeCache::parse(Some("3600")).unwrap(), Some(EdgeCache { ttl_seconds: 3600 }));
+        assert_eq!(EdgeCache::parse(Some("0")).unwrap(), None);
+        assert!(EdgeCache::parse(Some("1h")).is_err());
+    }
+
+    #[test]
+    fn test_url() {
+        assert_eq!(
+            EdgeCache::url("eth_getBlockByHash/1/0xabc"),
+            "https://rpc-cache.internal/eth_getBlockByHash/1/0xabc"
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use worker::*;
+
+/// Marks an R2 object written in the envelope format. Older objects are bare JSON,
+/// which can never start with these bytes.
+const MAGIC: &[u8; 4] = b"RPCE";
+/// Version 1: magic, version, encoding id, payload.
+/// Version 2 adds a length-prefixed JSON header between the encoding id and the payload.
+const FORMAT_VERSION: u8 = 2;
+const PREFIX_LEN: usize = MAGIC.len() + 2;
+const HEADER_LEN_BYTES: usize = 4;
+/// Upper bound on a header's size, so a bad length field can't trigger a huge allocation
+const MAX_HEADER_BYTES: usize = 4096;
+/// Bytes to read from the start of an object to be sure to get its whole header
+pub const HEADER_READ_BYTES: usize = PREFIX_LEN + HEADER_LEN_BYTES + MAX_HEADER_BYTES;
+
+/// Brotli quality; the maximum (11) is too slow for a request path
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+/// How an R2 entry's JSON payload is compressed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentEncoding {
+    /// Stored as is
+    Identity,
+    #[default]
+    Gzip,
+    Zstd,
+    Brotli,
+}
+
+impl ContentEncoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "identity",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Zstd => "zstd",
+            ContentEncoding::Brotli => "brotli",
+        }
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            ContentEncoding::Identity => 0,
+            ContentEncoding::Gzip => 1,
+            ContentEncoding::Zstd => 2,
+            ContentEncoding::Brotli => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(ContentEncoding::Identity),
+            1 => Some(ContentEncoding::Gzip),
+            2 => Some(ContentEncoding::Zstd),
+            3 => Some(ContentEncoding::Brotli),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Identity => Ok(data.to_vec()),
+            ContentEncoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            ContentEncoding::Zstd => Ok(ruzstd::encoding::compress_to_vec(
+                data,
+                ruzstd::encoding::CompressionLevel::Fastest,
+            )),
+            ContentEncoding::Brotli => {
+                let mut encoded = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(
+                        &mut encoded,
+                        4096,
+                        BROTLI_QUALITY,
+                        BROTLI_WINDOW_BITS,
+                    );
+                    encoder.write_all(data)?;
+                }
+                Ok(encoded)
+            }
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        match self {
+            ContentEncoding::Identity => decoded.extend_from_slice(data),
+            ContentEncoding::Gzip => {
+                flate2::read::GzDecoder::new(data).read_to_end(&mut decoded)?;
+            }
+            ContentEncoding::Zstd => {
+                ruzstd::decoding::StreamingDecoder::new(data)
+                    .map_err(std::io::Error::other)?
+                    .read_to_end(&mut decoded)?;
+            }
+            ContentEncoding::Brotli => {
+                brotli::Decompressor::new(data, 4096).read_to_end(&mut decoded)?;
+            }
+        }
+        Ok(decoded)
+    }
+}
+
+/// What a cache entry is and where it came from, written ahead of its payload
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EntryMetadata {
+    pub method: String,
+    pub chain_id: u64,
+    /// SHA-256 of the request params; unknown for entries migrated from older formats
+    pub params_digest: Option<String>,
+    /// Redacted URL of the upstream that produced the data
+    pub upstream: Option<String>,
+    pub created_at_ms: u64,
+    /// First and last block the data covers
+    pub block_range: Option<(u64, u64)>,
+    pub block_hash: Option<String>,
+}
+
+/// Header of a version 2 entry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntryHeader {
+    #[serde(flatten)]
+    pub metadata: EntryMetadata,
+    /// SHA-256 of the uncompressed JSON payload
+    pub checksum: String,
+}
+
+/// A stored object after unwrapping
+#[derive(Debug, PartialEq)]
+pub struct DecodedEntry {
+    pub value_json: Vec<u8>,
+    pub encoding: ContentEncoding,
+    /// 0 for bare JSON written before the envelope existed
+    pub version: u8,
+    /// Present from version 2 on
+    pub header: Option<EntryHeader>,
+}
+
+impl DecodedEntry {
+    /// Whether the entry should be rewritten in the current format
+    pub fn needs_migration(&self) -> bool {
+        self.version < FORMAT_VERSION
+    }
+}
+
+/// Digest identifying a request's params
+pub fn params_digest(params: &Value) -> String {
+    hex::encode(Sha256::digest(params.to_string().as_bytes()))
+}
+
+/// Checksum of a serialized JSON payload
+pub fn checksum(value_json: &[u8]) -> String {
+    hex::encode(Sha256::digest(value_json))
+}
+
+impl EntryHeader {
+    pub fn new(metadata: EntryMetadata, value_json: &[u8]) -> Self {
+        Self {
+            metadata,
+            checksum: checksum(value_json),
+        }
+    }
+}
+
+/// Wrap serialized JSON in the current envelope, compressing it with `encoding`.
+/// `header` must have been built from the same `value_json`.
+pub fn encode(value_json: &[u8], encoding: ContentEncoding, header: &EntryHeader) -> Result<Vec<u8>> {
+    let header = serde_json::to_vec(header)?;
+    if header.len() > MAX_HEADER_BYTES {
+        return Err(Error::RustError(format!("Cache entry header too large: {} bytes", header.len())));
+    }
+
+    let payload = encoding
+        .compress(value_json)
+        .map_err(|e| Error::RustError(format!("Failed to {} encode entry: {e}", encoding.as_str())))?;
+
+    let mut bytes = Vec::with_capacity(PREFIX_LEN + HEADER_LEN_BYTES + header.len() + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(FORMAT_VERSION);
+    bytes.push(encoding.id());
+    bytes.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&header);
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// Unwrap a stored object into its JSON bytes. Bare JSON and version 1 objects are still
+/// understood, so entries written by older releases stay readable.
+pub fn decode(bytes: &[u8]) -> Result<DecodedEntry> {
+    let (version, encoding, header, payload) = match split(bytes)? {
+        Some(parts) => parts,
+        None => {
+            return Ok(DecodedEntry {
+                value_json: bytes.to_vec(),
+                encoding: ContentEncoding::Identity,
+                version: 0,
+                header: None,
+            })
+        }
+    };
+
+    let value_json = encoding
+        .decompress(payload)
+        .map_err(|e| Error::RustError(format!("Failed to {} decode entry: {e}", encoding.as_str())))?;
+    Ok(DecodedEntry {
+        value_json,
+        encoding,
+        version,
+        header,
+    })
+}
+
+/// Read just the header from the first `HEADER_READ_BYTES` of an object.
+/// Objects without a header yield `None`.
+pub fn decode_header(prefix: &[u8]) -> Result<Option<EntryHeader>> {
+    Ok(split(prefix)?.and_then(|(_, _, header, _)| header))
+}
+
+/// Whether an object is enveloped in a version or encoding this release doesn't know, as
+/// written by a newer release. Such objects can't be read here but are not corrupt.
+pub fn is_newer_format(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+        && bytes.len() >= PREFIX_LEN
+        && (bytes[MAGIC.len()] > FORMAT_VERSION || ContentEncoding::from_id(bytes[MAGIC.len() + 1]).is_none())
+}
+
+type EnvelopeParts<'a> = (u8, ContentEncoding, Option<EntryHeader>, &'a [u8]);
+
+/// Split an enveloped object into version, encoding, header and payload;
+/// `None` for bare JSON. The payload may be incomplete when `bytes` is only a prefix.
+fn split(bytes: &[u8]) -> Result<Option<EnvelopeParts<'_>>> {
+    if !bytes.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    if bytes.len() < PREFIX_LEN {
+        return Err("Truncated cache entry header".into());
+    }
+
+    let version = bytes[MAGIC.len()];
+    let encoding = ContentEncoding::from_id(bytes[MAGIC.len() + 1]).ok_or_else(|| {
+        Error::RustError(format!("Unknown cache entry encoding {}", bytes[MAGIC.len() + 1]))
+    })?;
+    let rest = &bytes[PREFIX_LEN..];
+
+    match version {
+        1 => Ok(Some((version, encoding, None, rest))),
+        2 => {
+            if rest.len() < HEADER_LEN_BYTES {
+                return Err("Truncated cache entry header".into());
+            }
+            let (len_bytes, rest) = rest.split_at(HEADER_LEN_BYTES);
+            let header_len = u32::from_be_bytes(len_bytes.try_into().unwrap_or_default()) as usize;
+            if header_len > MAX_HEADER_BYTES || rest.len() < header_len {
+                return Err("Truncated cache entry header".into());
+            }
+            let (header, payload) = rest.split_at(header_len);
+            let header: EntryHeader = serde_json::from_slice(header)
+                .map_err(|e| Error::RustError(format!("Invalid cache entry header: {e}")))?;
+            Ok(Some((version, encoding, Some(header), payload)))
+        }
+        _ => Err(Error::RustError(format!("Unsupported cache entry version {version}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [ContentEncoding; 4] = [
+        ContentEncoding::Identity,
+        ContentEncoding::Gzip,
+        ContentEncoding::Zstd,
+        ContentEncoding::Brotli,
+    ];
+
+    fn sample_json() -> Vec<u8> {
+        let logs: Vec<serde_json::Value> = (0..200)
+            .map(|i| {
+                serde_json::json!({
+                    "address": "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+                    "blockNumber": format!("0x{:x}", 18_000_000 + i),
+                    "logIndex": format!("0x{i:x}"),
+                    "removed": false,
+                })
+            })
+            .collect();
+        serde_json::to_vec(&logs).unwrap()
+    }
+
+    fn metadata() -> EntryMetadata {
+        EntryMetadata {
+            method: "eth_getLogs".to_string(),
+            chain_id: 1,
+            params_digest: Some(params_digest(&serde_json::json!([{"fromBlock": "0x1"}]))),
+            upstream: Some("https://rpc.example.com/***".to_string()),
+            created_at_ms: 1_700_000_000_000,
+            block_range: Some((18_000_000, 18_000_199)),
+            block_hash: None,
+        }
+    }
+
+    /// Version 1 layout, as written before entries carried a header
+    fn encode_v1(value_json: &[u8], encoding: ContentEncoding) -> Vec<u8> {
+        let mut bytes = b"RPCE\x01".to_vec();
+        bytes.push(encoding.id());
+        bytes.extend_from_slice(&encoding.compress(value_json).unwrap());
+        bytes
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let json = sample_json();
+        for encoding in ALL {
+            let header = EntryHeader::new(metadata(), &json);
+            let decoded = decode(&encode(&json, encoding, &header).unwrap()).unwrap();
+            assert_eq!(decoded.value_json, json, "{encoding:?}");
+            assert_eq!(decoded.encoding, encoding);
+            assert!(!decoded.needs_migration());
+
+            let header = decoded.header.unwrap();
+            assert_eq!(header.metadata, metadata());
+            assert_eq!(header.checksum, checksum(&json));
+        }
+    }
+
+    #[test]
+    fn test_compression_shrinks_repetitive_json() {
+        let json = sample_json();
+        for encoding in [ContentEncoding::Gzip, ContentEncoding::Zstd, ContentEncoding::Brotli] {
+            let encoded = encode(&json, encoding, &EntryHeader::new(metadata(), &json)).unwrap();
+            assert!(encoded.len() * 4 < json.len(), "{encoding:?}: {} bytes", encoded.len());
+        }
+    }
+
+    #[test]
+    fn test_older_formats_are_read_and_flagged_for_migration() {
+        let json = br#"{"blockNumber":"0x1"}"#;
+
+        let bare = decode(json).unwrap();
+        assert_eq!(bare.value_json, json);
+        assert_eq!(bare.version, 0);
+        assert!(bare.needs_migration());
+
+        let v1 = decode(&encode_v1(json, ContentEncoding::Gzip)).unwrap();
+        assert_eq!(v1.value_json, json);
+        assert_eq!(v1.encoding, ContentEncoding::Gzip);
+        assert_eq!(v1.header, None);
+        assert!(v1.needs_migration());
+    }
+
+    #[test]
+    fn test_header_from_prefix() {
+        let json = sample_json();
+        let encoded = encode(&json, ContentEncoding::Gzip, &EntryHeader::new(metadata(), &json)).unwrap();
+        let prefix = &encoded[..HEADER_READ_BYTES.min(encoded.len())];
+        assert_eq!(decode_header(prefix).unwrap().unwrap().metadata, metadata());
+        assert_eq!(decode_header(br#"[]"#).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_headers_rejected() {
+        assert!(decode(b"RPCE").is_err());
+        assert!(decode(b"RPCE\x09\x00{}").is_err());
+        assert!(decode(b"RPCE\x01\x07{}").is_err());
+        assert!(decode(b"RPCE\x01\x01not gzip").is_err());
+        assert!(decode(b"RPCE\x02\x00\x00\x00\xff\xff{}").is_err());
+        assert!(decode(b"RPCE\x02\x00\x00\x00\x00\x02{}[]").is_err());
+    }
+
+    #[test]
+    fn test_newer_formats_recognized() {
+        assert!(is_newer_format(b"RPCE\x09\x00{}"));
+        assert!(is_newer_format(b"RPCE\x02\x07{}"));
+
+        let json = sample_json();
+        let current = encode(&json, ContentEncoding::Gzip, &EntryHeader::new(metadata(), &json)).unwrap();
+        assert!(!is_newer_format(&current));
+        assert!(!is_newer_format(&encode_v1(&json, ContentEncoding::Zstd)));
+        assert!(!is_newer_format(&json));
+        assert!(!is_newer_format(b"RPCE"));
+    }
+
+    #[test]
+    fn test_encoding_names() {
+        for encoding in ALL {
+            let name = serde_json::to_value(encoding).unwrap();
+            assert_eq!(name, encoding.as_str());
+        }
+    }
+}
@@ -0,0 +1,856 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use worker::*;
+
+use crate::cache::{CacheManager, CacheStatus, CacheTier, Cacheability, GetLogsRequest};
+use crate::chains::redact_url;
+use crate::directives::CacheDirectives;
+use crate::envelope::{self, ContentEncoding, EntryMetadata};
+use crate::metrics::{self, Metric};
+use crate::rpc::{RpcError, RpcRequest, RpcResponse, INVALID_PARAMS, RESOURCE_UNAVAILABLE};
+use crate::transactions;
+use crate::upstream::{fetch_upstream, UpstreamResponse};
+use crate::utils::{is_block_hash, parse_hex_to_u64};
+
+/// A cached RPC method: how to validate its params, derive its cache key
+/// and decide whether requests and responses are cacheable.
+/// The shared request pipeline lives in `handle_cached`.
+pub trait MethodHandler {
+    /// JSON-RPC method name this handler serves
+    fn method(&self) -> &'static str;
+
+    /// Storage tier for this method's entries
+    fn tier(&self) -> CacheTier {
+        CacheTier::R2
+    }
+
+    /// Compression of this method's R2 entries
+    fn encoding(&self) -> ContentEncoding {
+        ContentEncoding::Gzip
+    }
+
+    /// Validate the request params before touching the cache or upstream
+    fn validate(&self, params: &Value) -> std::result::Result<(), RpcError>;
+
+    /// Cache key for the given (validated) params
+    fn cache_key(&self, cache_manager: &CacheManager, params: &Value) -> Option<String>;
+
+    /// Whether a cached entry may exist for this request
+    fn lookup_policy(&self, _params: &Value) -> Cacheability {
+        Cacheability::Final
+    }
+
+    /// Whether the upstream result may be stored
+    fn store_policy(&self, params: &Value, result: &Value) -> Cacheability;
+
+    /// Blocks a stored result covers, recorded so entries can be purged by block range
+    fn block_range(&self, params: &Value, result: &Value) -> Option<(u64, u64)> {
+        match self.store_policy(params, result) {
+            Cacheability::AtBlock(block_number) => Some((block_number, block_number)),
+            _ => response_block_number(result).map(|block_number| (block_number, block_number)),
+        }
+    }
+
+    /// Called once a final result has been stored for these params
+    fn on_stored(&self, _params: &Value) {}
+
+    /// Hash of the block a stored result belongs to, recorded in the entry header
+    fn block_hash(&self, _params: &Value, result: &Value) -> Option<String> {
+        response_block_hash(result)
+    }
+}
+
+/// Maps method names to their cache handlers
+pub struct HandlerRegistry {
+    handlers: HashMap<&'static str, Box<dyn MethodHandler>>,
+}
+
+impl HandlerRegistry {
+    /// Registry with all built-in cached methods
+    pub fn new() -> Self {
+        let mut registry = Self {
+            handlers: HashMap::new(),
+        };
+
+        registry.register(Box::new(GetLogs));
+        registry.register(Box::new(GetBlockByNumber));
+        registry.register(Box::new(GetTransactionReceipt));
+        registry.register(Box::new(GetBlockByHash));
+        registry.register(Box::new(GetBlockReceipts));
+        registry.register(Box::new(TraceBlock::new("debug_traceBlockByNumber")));
+        registry.register(Box::new(TraceBlock::new("debug_traceBlockByHash")));
+
+        registry
+    }
+
+    pub fn register(&mut self, handler: Box<dyn MethodHandler>) {
+        self.handlers.insert(handler.method(), handler);
+    }
+
+    pub fn get(&self, method: &str) -> Option<&dyn MethodHandler> {
+        self.handlers.get(method).map(|handler| handler.as_ref())
+    }
+
+    /// Names of all registered methods
+    pub fn methods(&self) -> Vec<&'static str> {
+        self.handlers.keys().copied().collect()
+    }
+}
+
+impl Default for HandlerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How a response was produced, reported to clients in `X-Cache*` and `X-Upstream*` headers
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheOutcome {
+    pub status: CacheStatus,
+    pub key: Option<String>,
+    pub tier: Option<CacheTier>,
+    /// Redacted URL of the upstream that answered
+    pub upstream: Option<String>,
+    pub upstream_latency_ms: Option<u64>,
+}
+
+impl CacheOutcome {
+    pub fn bypass() -> Self {
+        Self {
+            status: CacheStatus::Bypass,
+            key: None,
+            tier: None,
+            upstream: None,
+            upstream_latency_ms: None,
+        }
+    }
+
+    fn cached(status: CacheStatus, key: &str, tier: CacheTier) -> Self {
+        Self {
+            status,
+            key: Some(key.to_string()),
+            tier: Some(tier),
+            ..Self::bypass()
+        }
+    }
+
+    /// Record which upstream answered and how long it took
+    pub fn with_upstream(mut self, upstream_response: &UpstreamResponse) -> Self {
+        self.upstream = upstream_response.upstream.as_deref().map(redact_url);
+        self.upstream_latency_ms = upstream_response.latency_ms;
+        self
+    }
+
+    /// Response headers describing this outcome
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("X-Cache", self.status.as_str().to_string())];
+        if let Some(key) = &self.key {
+            headers.push(("X-Cache-Key", key.clone()));
+        }
+        if let Some(tier) = self.tier {
+            headers.push(("X-Cache-Tier", tier.as_str().to_string()));
+        }
+        if let Some(upstream) = &self.upstream {
+            headers.push(("X-Upstream", upstream.clone()));
+        }
+        if let Some(latency_ms) = self.upstream_latency_ms {
+            headers.push(("X-Upstream-Latency-Ms", latency_ms.to_string()));
+        }
+        headers
+    }
+}
+
+/// Shared pipeline for cached methods: validate, look up, fetch from upstream, store.
+/// Per-method behavior is adjusted by the configured `MethodPolicy` and the client's `CacheDirectives`.
+pub async fn handle_cached(
+    handler: &dyn MethodHandler,
+    rpc_request: &RpcRequest,
+    cache_manager: &CacheManager,
+    directives: CacheDirectives,
+) -> Result<(RpcResponse, CacheOutcome)> {
+    let method = handler.method();
+    let params = &rpc_request.params;
+    let chain_id = cache_manager.chain().id_str();
+
+    if let Err(error) = handler.validate(params) {
+        return Ok((RpcResponse::error(rpc_request.response_id(), error), CacheOutcome::bypass()));
+    }
+
+    let policy = cache_manager.method_policy(method);
+    let tier = policy.tier.unwrap_or_else(|| handler.tier());
+
+    // Degraded mode: while the tier's storage is unavailable, proxy as if the method were uncached
+    let degraded = policy.enabled && !cache_manager.tier_available(tier);
+    if degraded {
+        console_log!("{}: {} tier unavailable, proxying without cache", method, tier.as_str());
+        metrics::record(Metric::CacheDegraded, &chain_id, method);
+    }
+    if !policy.enabled || degraded {
+        if directives.only_if_cached {
+            return Ok((not_cached_response(rpc_request), CacheOutcome::bypass()));
+        }
+        if !policy.enabled {
+            console_log!("{}: caching disabled by policy", method);
+        }
+        let upstream_response = fetch_upstream(rpc_request, cache_manager.chain()).await?;
+        let outcome = CacheOutcome::bypass().with_upstream(&upstream_response);
+        return Ok((upstream_response.response, outcome));
+    }
+
+    let encoding = policy.encoding.unwrap_or_else(|| handler.encoding());
+    // Non-final data may be kept briefly in memory when the policy sets a TTL
+    let keep_non_final = tier != CacheTier::Memory && policy.ttl_seconds.is_some();
+
+    let cache_key = handler.cache_key(cache_manager, params);
+    let mut looked_up = false;
+
+    // Try to get from cache
+    if let Some(cache_key) = cache_key.as_ref().filter(|_| !directives.skips_read()) {
+        if cache_manager.is_cacheable(handler.lookup_policy(params), &policy).await {
+            looked_up = true;
+            match cache_manager.get_from_cache(tier, cache_key, &policy, encoding).await {
+                Ok(Some(cached)) => {
+                    console_log!("{} cache HIT for key {}", method, cache_key);
+                    metrics::record(Metric::CacheHit, &chain_id, method);
+                    let outcome = CacheOutcome::cached(CacheStatus::Hit, cache_key, tier);
+                    return Ok((RpcResponse::success(rpc_request.response_id(), cached), outcome));
+                }
+                Ok(None) => console_log!("{} cache MISS for key {}", method, cache_key),
+                // A failing cache read must not fail the request: treat it as a miss
+                Err(e) => {
+                    console_log!(
+                        "ERROR: {} cache read failed for key {}, proxying: {:?}",
+                        method,
+                        cache_key,
+                        e
+                    );
+                    metrics::record(Metric::CacheReadFailure, &chain_id, method);
+                }
+            }
+        } else {
+            console_log!("{}: blocks too recent, skipping cache", method);
+        }
+
+        if keep_non_final {
+            if let Some(cached) = cache_manager.get_from_memory(cache_key) {
+                console_log!("{} non-final cache HIT for key {}", method, cache_key);
+                metrics::record(Metric::CacheHit, &chain_id, method);
+                let outcome = CacheOutcome::cached(CacheStatus::Stale, cache_key, CacheTier::Memory);
+                return Ok((RpcResponse::success(rpc_request.response_id(), cached), outcome));
+            }
+        }
+    }
+
+    if directives.only_if_cached {
+        let outcome = match &cache_key {
+            Some(cache_key) => CacheOutcome::cached(CacheStatus::Miss, cache_key, tier),
+            None => CacheOutcome::bypass(),
+        };
+        return Ok((not_cached_response(rpc_request), outcome));
+    }
+
+    // Cache miss or not cacheable - fetch from upstream. Directives, recent blocks and keyless
+    // requests skip the lookup, which is counted apart so misses keep meaning failed lookups.
+    let counter = if looked_up { Metric::CacheMiss } else { Metric::CacheBypass };
+    metrics::record(counter, &chain_id, method);
+    let upstream_response = fetch_upstream(rpc_request, cache_manager.chain()).await?;
+    let outcome = match &cache_key {
+        Some(cache_key) if looked_up => CacheOutcome::cached(CacheStatus::Miss, cache_key, tier),
+        Some(cache_key) if directives.skips_read() => {
+            CacheOutcome::cached(CacheStatus::Bypass, cache_key, tier)
+        }
+        _ => CacheOutcome::bypass(),
+    }
+    .with_upstream(&upstream_response);
+    let response = upstream_response.response;
+
+    // Data from an upstream that may be serving another chain must never be cached
+    if !upstream_response.chain_verified {
+        console_log!(
+            "{}: chain id of upstream {:?} not verified, skipping cache",
+            method,
+            outcome.upstream
+        );
+        return Ok((response, outcome));
+    }
+
+    if directives.no_store {
+        console_log!("{}: no-store requested, skipping cache", method);
+        return Ok((response, outcome));
+    }
+
+    let cache_key = match cache_key {
+        Some(cache_key) => cache_key,
+        None => return Ok((response, outcome)),
+    };
+    let result = response.result.as_ref().filter(|result| !result.is_null());
+    let is_final = match result {
+        Some(result) => {
+            cache_manager
+                .is_cacheable(handler.store_policy(params, result), &policy)
+                .await
+        }
+        None => false,
+    };
+
+    // A refresh whose fresh result must not be cached drops the suspicious entry instead
+    if directives.refresh && !is_final {
+        console_log!("{}: refreshed result not cacheable, deleting {}", method, cache_key);
+        if let Err(e) = cache_manager.delete_entry(&cache_key).await {
+            console_log!("ERROR: Failed to delete {}: {:?}", cache_key, e);
+        }
+        return Ok((response, outcome));
+    }
+
+    // Store in cache if applicable
+    if let Some(result) = result {
+        let metadata = EntryMetadata {
+            method: method.to_string(),
+            chain_id: cache_manager.chain().id,
+            params_digest: Some(envelope::params_digest(params)),
+            upstream: outcome.upstream.clone(),
+            created_at_ms: Date::now().as_millis(),
+            block_range: handler.block_range(params, result),
+            block_hash: handler.block_hash(params, result),
+        };
+        let stored = if is_final {
+            Some(
+                cache_manager
+                    .store_in_cache(tier, &cache_key, result, &policy, encoding, metadata)
+                    .await,
+            )
+        } else if keep_non_final {
+            Some(
+                cache_manager
+                    .store_in_cache(CacheTier::Memory, &cache_key, result, &policy, encoding, metadata)
+                    .await,
+            )
+        } else {
+            console_log!("{}: result is too recent or not final, skipping cache", method);
+            None
+        };
+
+        match stored {
+            Some(Ok(())) => {
+                metrics::record(Metric::CacheStore, &chain_id, method);
+                if is_final {
+                    handler.on_stored(params);
+                }
+            }
+            Some(Err(e)) => {
+                console_log!("ERROR: Failed to store {} in cache: {:?}", cache_key, e);
+                metrics::record(Metric::CacheStoreFailure, &chain_id, method);
+            }
+            None => metrics::record(Metric::CacheSkippedRecent, &chain_id, method),
+        }
+    }
+
+    Ok((response, outcome))
+}
+
+/// Answer for cache-only requests whose data is not cached
+pub fn not_cached_response(rpc_request: &RpcRequest) -> RpcResponse {
+    RpcResponse::error(
+        rpc_request.response_id(),
+        RpcError::new(RESOURCE_UNAVAILABLE, "Not available from cache (cache-only request)"),
+    )
+}
+
+/// First positional param as a string (hash or block identifier)
+fn first_string_param<'a>(params: &'a Value, name: &str) -> std::result::Result<&'a str, RpcError> {
+    match params.as_array().and_then(|arr| arr.first()) {
+        Some(Value::String(s)) => Ok(s),
+        Some(_) => Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("Invalid params: {name} must be a string"),
+        )),
+        None => Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("Invalid params: missing {name}"),
+        )),
+    }
+}
+
+/// Cacheability of a block number param; tags like "latest" are never cached
+fn block_number_cacheability(block_id: &str) -> Cacheability {
+    match block_id {
+        "latest" | "pending" | "earliest" => Cacheability::Never,
+        _ => parse_hex_to_u64(block_id)
+            .map(Cacheability::AtBlock)
+            .unwrap_or(Cacheability::Never),
+    }
+}
+
+/// Extract the block number from a receipt, trace or block object
+fn response_block_number(value: &Value) -> Option<u64> {
+    value
+        .get("blockNumber")
+        .or_else(|| value.get("number"))
+        .and_then(|v| v.as_str())
+        .filter(|bn| !bn.is_empty() && *bn != "null")
+        .and_then(|bn| parse_hex_to_u64(bn).ok())
+}
+
+/// Block hash of a receipt, log or block object, or of a list whose entries share one block
+fn response_block_hash(value: &Value) -> Option<String> {
+    match value {
+        Value::Array(items) => {
+            let first = response_block_hash(items.first()?)?;
+            items
+                .iter()
+                .all(|item| response_block_hash(item).as_ref() == Some(&first))
+                .then_some(first)
+        }
+        Value::Object(object) => object
+            .get("blockHash")
+            // Block objects carry their own hash alongside the parent's
+            .or_else(|| object.get("parentHash").and(object.get("hash")))
+            .and_then(|v| v.as_str())
+            .map(str::to_string),
+        _ => None,
+    }
+}
+
+fn response_cacheability(value: &Value) -> Cacheability {
+    response_block_number(value)
+        .map(Cacheability::AtBlock)
+        .unwrap_or(Cacheability::Never)
+}
+
+/// eth_getLogs: cached in R2 when the whole block range is far enough from the tip
+pub struct GetLogs;
+
+impl GetLogs {
+    /// Numeric block range of the filter; "latest" and "pending" fail to parse, "earliest" is block 0
+    fn numeric_range(params: &Value) -> Option<(u64, u64)> {
+        let filter: GetLogsRequest = serde_json::from_value(params.get(0)?.clone()).ok()?;
+        let from = parse_hex_to_u64(filter.from_block.as_deref()?).ok()?;
+        let to = parse_hex_to_u64(filter.to_block.as_deref()?).ok()?;
+        Some((from, to))
+    }
+
+    fn range_cacheability(params: &Value) -> Cacheability {
+        Self::numeric_range(params)
+            .map(|(_, to)| Cacheability::AtBlock(to))
+            .unwrap_or(Cacheability::Never)
+    }
+}
+
+impl MethodHandler for GetLogs {
+    fn method(&self) -> &'static str {
+        "eth_getLogs"
+    }
+
+    fn validate(&self, params: &Value) -> std::result::Result<(), RpcError> {
+        let filter = match params.as_array().and_then(|arr| arr.first()) {
+            Some(filter) => filter,
+            None => return Err(RpcError::new(INVALID_PARAMS, "Invalid params")),
+        };
+
+        serde_json::from_value::<GetLogsRequest>(filter.clone())
+            .map(|_| ())
+            .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid params: {e}")))
+    }
+
+    fn cache_key(&self, cache_manager: &CacheManager, params: &Value) -> Option<String> {
+        params
+            .get(0)
+            .map(|filter| cache_manager.generate_logs_cache_key(filter))
+    }
+
+    fn lookup_policy(&self, params: &Value) -> Cacheability {
+        Self::range_cacheability(params)
+    }
+
+    fn store_policy(&self, params: &Value, _result: &Value) -> Cacheability {
+        Self::range_cacheability(params)
+    }
+
+    fn block_range(&self, params: &Value, _result: &Value) -> Option<(u64, u64)> {
+        Self::numeric_range(params)
+    }
+
+    /// Logs carry the hash of the block that emitted them, which only identifies the queried
+    /// range when it is a single block
+    fn block_hash(&self, params: &Value, result: &Value) -> Option<String> {
+        match Self::numeric_range(params) {
+            Some((from, to)) if from == to => response_block_hash(result),
+            _ => None,
+        }
+    }
+}
+
+/// eth_getBlockByNumber: short-lived in-memory cache, since "latest" moves every block
+pub struct GetBlockByNumber;
+
+impl MethodHandler for GetBlockByNumber {
+    fn method(&self) -> &'static str {
+        "eth_getBlockByNumber"
+    }
+
+    fn tier(&self) -> CacheTier {
+        CacheTier::Memory
+    }
+
+    fn validate(&self, _params: &Value) -> std::result::Result<(), RpcError> {
+        Ok(())
+    }
+
+    fn cache_key(&self, cache_manager: &CacheManager, params: &Value) -> Option<String> {
+        let block_number = params.get(0).and_then(|v| v.as_str()).unwrap_or("latest");
+        let full_transactions = params.get(1).and_then(|v| v.as_bool()).unwrap_or(false);
+        Some(cache_manager.generate_block_by_number_cache_key(block_number, full_transactions))
+    }
+
+    fn store_policy(&self, _params: &Value, _result: &Value) -> Cacheability {
+        Cacheability::Final
+    }
+}
+
+/// eth_getTransactionReceipt: cached once the transaction is mined. Receipts of
+/// transactions submitted through this proxy skip the lookup until a mined receipt is stored.
+/// Submissions are tracked per isolate, so a lookup served by another isolate may still be
+/// answered from the cache.
+pub struct GetTransactionReceipt;
+
+impl MethodHandler for GetTransactionReceipt {
+    fn method(&self) -> &'static str {
+        "eth_getTransactionReceipt"
+    }
+
+    fn validate(&self, params: &Value) -> std::result::Result<(), RpcError> {
+        first_string_param(params, "transaction hash").map(|_| ())
+    }
+
+    fn cache_key(&self, cache_manager: &CacheManager, params: &Value) -> Option<String> {
+        let tx_hash = first_string_param(params, "transaction hash").ok()?;
+        Some(cache_manager.generate_tx_receipt_cache_key(tx_hash))
+    }
+
+    fn encoding(&self) -> ContentEncoding {
+        // Single receipts are small enough that compression isn't worth the CPU time
+        ContentEncoding::Identity
+    }
+
+    fn lookup_policy(&self, params: &Value) -> Cacheability {
+        match first_string_param(params, "transaction hash") {
+            Ok(tx_hash) if transactions::is_pending(tx_hash) => Cacheability::Never,
+            _ => Cacheability::Final,
+        }
+    }
+
+    fn on_stored(&self, params: &Value) {
+        if let Ok(tx_hash) = first_string_param(params, "transaction hash") {
+            transactions::mark_mined(tx_hash);
+        }
+    }
+
+    fn store_policy(&self, _params: &Value, receipt: &Value) -> Cacheability {
+        // A receipt with a blockNumber has been mined
+        let confirmed = receipt
+            .get("blockNumber")
+            .and_then(|v| v.as_str())
+            .map(|bn| !bn.is_empty() && bn != "null")
+            .unwrap_or(false);
+
+        if confirmed {
+            Cacheability::Final
+        } else {
+            Cacheability::Never
+        }
+    }
+}
+
+/// eth_getBlockByHash: cached when the returned block is old enough
+pub struct GetBlockByHash;
+
+impl MethodHandler for GetBlockByHash {
+    fn method(&self) -> &'static str {
+        "eth_getBlockByHash"
+    }
+
+    fn validate(&self, params: &Value) -> std::result::Result<(), RpcError> {
+        first_string_param(params, "block hash").map(|_| ())
+    }
+
+    fn cache_key(&self, cache_manager: &CacheManager, params: &Value) -> Option<String> {
+        let block_hash = first_string_param(params, "block hash").ok()?;
+        Some(cache_manager.generate_block_by_hash_cache_key(block_hash))
+    }
+
+    fn store_policy(&self, _params: &Value, block: &Value) -> Cacheability {
+        block
+            .get("number")
+            .and_then(|v| v.as_str())
+            .map(block_number_cacheability)
+            .unwrap_or(Cacheability::Never)
+    }
+}
+
+/// eth_getBlockReceipts: keyed by block number or hash. For hashes the block
+/// number is taken from the first receipt in the response.
+pub struct GetBlockReceipts;
+
+impl MethodHandler for GetBlockReceipts {
+    fn method(&self) -> &'static str {
+        "eth_getBlockReceipts"
+    }
+
+    fn validate(&self, params: &Value) -> std::result::Result<(), RpcError> {
+        first_string_param(params, "block identifier").map(|_| ())
+    }
+
+    fn cache_key(&self, cache_manager: &CacheManager, params: &Value) -> Option<String> {
+        let block_id = first_string_param(params, "block identifier").ok()?;
+        Some(cache_manager.generate_block_receipts_cache_key(block_id))
+    }
+
+    fn store_policy(&self, params: &Value, receipts: &Value) -> Cacheability {
+        let block_id = match first_string_param(params, "block identifier") {
+            Ok(block_id) => block_id,
+            Err(_) => return Cacheability::Never,
+        };
+
+        if is_block_hash(block_id) {
+            receipts
+                .as_array()
+                .and_then(|arr| arr.first())
+                .map(response_cacheability)
+                .unwrap_or(Cacheability::Never)
+        } else {
+            block_number_cacheability(block_id)
+        }
+    }
+}
+
+/// debug_traceBlockByNumber / debug_traceBlockByHash
+pub struct TraceBlock {
+    method: &'static str,
+}
+
+impl TraceBlock {
+    pub fn new(method: &'static str) -> Self {
+        Self { method }
+    }
+}
+
+impl MethodHandler for TraceBlock {
+    fn method(&self) -> &'static str {
+        self.method
+    }
+
+    fn validate(&self, params: &Value) -> std::result::Result<(), RpcError> {
+        first_string_param(params, "block identifier").map(|_| ())
+    }
+
+    fn cache_key(&self, cache_manager: &CacheManager, params: &Value) -> Option<String> {
+        let block_id = first_string_param(params, "block identifier").ok()?;
+        Some(cache_manager.generate_trace_cache_key(self.method, block_id))
+    }
+
+    fn store_policy(&self, params: &Value, trace: &Value) -> Cacheability {
+        let block_id = match first_string_param(params, "block identifier") {
+            Ok(block_id) => block_id,
+            Err(_) => return Cacheability::Never,
+        };
+
+        if !is_block_hash(block_id) {
+            return block_number_cacheability(block_id);
+        }
+
+        // Transaction-style traces carry no block number, so their age is unknown
+        if trace.get("structLogs").is_some() {
+            return Cacheability::Never;
+        }
+        response_cacheability(trace)
+    }
+
+    fn block_hash(&self, params: &Value, _trace: &Value) -> Option<String> {
+        // Traces don't name their block; only a by-hash request tells which one it was
+        first_string_param(params, "block identifier")
+            .ok()
+            .filter(|block_id| is_block_hash(block_id))
+            .map(str::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const BLOCK_HASH: &str = "0x1234567890123456789012345678901234567890123456789012345678901234";
+
+    #[test]
+    fn test_response_block_hash() {
+        let receipt = json!({"blockHash": BLOCK_HASH, "blockNumber": "0x1"});
+        assert_eq!(response_block_hash(&receipt).as_deref(), Some(BLOCK_HASH));
+
+        let block = json!({"hash": BLOCK_HASH, "parentHash": "0xabc", "number": "0x1"});
+        assert_eq!(response_block_hash(&block).as_deref(), Some(BLOCK_HASH));
+
+        let same_block = json!([{"blockHash": BLOCK_HASH}, {"blockHash": BLOCK_HASH}]);
+        assert_eq!(response_block_hash(&same_block).as_deref(), Some(BLOCK_HASH));
+
+        let many_blocks = json!([{"blockHash": BLOCK_HASH}, {"blockHash": "0xother"}]);
+        assert_eq!(response_block_hash(&many_blocks), None);
+
+        // A transaction has a hash but no parentHash
+        assert_eq!(response_block_hash(&json!({"hash": "0xtx"})), None);
+
+        let trace = TraceBlock::new("debug_traceBlockByHash");
+        assert_eq!(trace.block_hash(&json!([BLOCK_HASH]), &json!([])).as_deref(), Some(BLOCK_HASH));
+        assert_eq!(trace.block_hash(&json!(["0x10"]), &json!([])), None);
+    }
+
+    #[test]
+    fn test_logs_block_hash() {
+        let logs = GetLogs;
+        let result = json!([{"blockHash": BLOCK_HASH, "blockNumber": "0x96"}]);
+
+        // All logs of 0x64..0xc8 were emitted in block 0x96, whose hash says nothing about 0xc8
+        assert_eq!(logs.block_hash(&json!([{"fromBlock": "0x64", "toBlock": "0xc8"}]), &result), None);
+        assert_eq!(
+            logs.block_hash(&json!([{"fromBlock": "0x96", "toBlock": "0x96"}]), &result).as_deref(),
+            Some(BLOCK_HASH)
+        );
+    }
+
+    #[test]
+    fn test_registry_contains_cached_methods() {
+        let registry = HandlerRegistry::new();
+
+        for method in [
+            "eth_getLogs",
+            "eth_getBlockByNumber",
+            "eth_getTransactionReceipt",
+            "eth_getBlockByHash",
+            "eth_getBlockReceipts",
+            "debug_traceBlockByNumber",
+            "debug_traceBlockByHash",
+        ] {
+            assert_eq!(registry.get(method).map(|h| h.method()), Some(method));
+        }
+        assert!(registry.get("eth_chainId").is_none());
+    }
+
+    #[test]
+    fn test_param_validation() {
+        let registry = HandlerRegistry::new();
+        let receipt = registry.get("eth_getTransactionReceipt").unwrap();
+
+        assert!(receipt.validate(&json!(["0xabc"])).is_ok());
+        assert_eq!(receipt.validate(&json!([])).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(receipt.validate(&json!([1])).unwrap_err().code, INVALID_PARAMS);
+
+        let logs = registry.get("eth_getLogs").unwrap();
+        assert!(logs.validate(&json!([{"fromBlock": "0x1", "toBlock": "0x2"}])).is_ok());
+        assert!(logs.validate(&json!([{"fromBlock": 1}])).is_err());
+        assert!(logs.validate(&json!([])).is_err());
+    }
+
+    #[test]
+    fn test_logs_range_cacheability() {
+        let logs = GetLogs;
+
+        assert_eq!(
+            logs.lookup_policy(&json!([{"fromBlock": "0x64", "toBlock": "0xc8"}])),
+            Cacheability::AtBlock(200)
+        );
+        assert_eq!(
+            logs.lookup_policy(&json!([{"fromBlock": "0x64", "toBlock": "latest"}])),
+            Cacheability::Never
+        );
+        assert_eq!(
+            logs.lookup_policy(&json!([{"blockHash": BLOCK_HASH}])),
+            Cacheability::Never
+        );
+    }
+
+    #[test]
+    fn test_receipt_store_policy() {
+        let receipt = GetTransactionReceipt;
+        let params = json!(["0xabc"]);
+
+        assert_eq!(
+            receipt.store_policy(&params, &json!({"blockNumber": "0x64"})),
+            Cacheability::Final
+        );
+        assert_eq!(
+            receipt.store_policy(&params, &json!({"transactionHash": "0xabc"})),
+            Cacheability::Never
+        );
+    }
+
+    #[test]
+    fn test_block_receipts_store_policy() {
+        let receipts = GetBlockReceipts;
+
+        assert_eq!(
+            receipts.store_policy(&json!(["0x64"]), &json!([])),
+            Cacheability::AtBlock(100)
+        );
+        assert_eq!(
+            receipts.store_policy(&json!(["latest"]), &json!([])),
+            Cacheability::Never
+        );
+        assert_eq!(
+            receipts.store_policy(&json!([BLOCK_HASH]), &json!([{"blockNumber": "0xc8"}])),
+            Cacheability::AtBlock(200)
+        );
+        assert_eq!(
+            receipts.store_policy(&json!([BLOCK_HASH]), &json!([])),
+            Cacheability::Never
+        );
+    }
+
+    #[test]
+    fn test_trace_by_hash_store_policy() {
+        let trace = TraceBlock::new("debug_traceBlockByHash");
+
+        assert_eq!(
+            trace.store_policy(&json!([BLOCK_HASH]), &json!({"structLogs": []})),
+            Cacheability::Never
+        );
+        assert_eq!(
+            trace.store_policy(&json!([BLOCK_HASH]), &json!({"blockNumber": "0x10"})),
+            Cacheability::AtBlock(16)
+        );
+        assert_eq!(
+            trace.store_policy(&json!(["0x10"]), &json!([])),
+            Cacheability::AtBlock(16)
+        );
+    }
+
+    #[test]
+    fn test_block_range_for_purges() {
+        let params = json!([{"fromBlock": "0x64", "toBlock": "0xc8"}]);
+        assert_eq!(GetLogs.block_range(&params, &json!([])), Some((100, 200)));
+
+        let receipt = json!({"blockNumber": "0x64"});
+        assert_eq!(GetTransactionReceipt.block_range(&json!(["0xabc"]), &receipt), Some((100, 100)));
+
+        assert_eq!(GetBlockReceipts.block_range(&json!(["0x10"]), &json!([])), Some((16, 16)));
+    }
+
+    #[test]
+    fn test_cache_outcome_headers() {
+        assert_eq!(CacheOutcome::bypass().headers(), vec![("X-Cache", "BYPASS".to_string())]);
+
+        let outcome = CacheOutcome {
+            upstream: Some("https://rpc.example/***".to_string()),
+            upstream_latency_ms: Some(42),
+            ..CacheOutcome::cached(CacheStatus::Miss, "eth_getLogs/1/abc", CacheTier::R2)
+        };
+        assert_eq!(
+            outcome.headers(),
+            vec![
+                ("X-Cache", "MISS".to_string()),
+                ("X-Cache-Key", "eth_getLogs/1/abc".to_string()),
+                ("X-Cache-Tier", "r2".to_string()),
+                ("X-Upstream", "https://rpc.example/***".to_string()),
+                ("X-Upstream-Latency-Ms", "42".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,546 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use wasm_bindgen::JsValue;
+use worker::*;
+
+/// D1 database holding one row per stored R2 or KV entry (schema in `migrations/`)
+const INDEX_BINDING: &str = "CACHE_INDEX";
+
+/// Minimum time between flushes of buffered hit counts
+const HIT_FLUSH_INTERVAL_MS: u64 = 10_000;
+/// Upper bound on distinct keys with buffered hits per isolate
+const MAX_PENDING_HITS: usize = 1_000;
+
+/// Indexed facts about a stored entry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub key: String,
+    pub method: String,
+    pub chain_id: u64,
+    pub from_block: Option<u64>,
+    pub to_block: Option<u64>,
+    pub block_hash: Option<String>,
+    /// Backend holding the entry: `kv` or `r2`
+    pub store: String,
+    /// Stored (compressed) size in bytes
+    pub size: u64,
+    pub created_at_ms: u64,
+    #[serde(default)]
+    pub hits: u64,
+    #[serde(default)]
+    pub last_hit_ms: Option<u64>,
+}
+
+/// Entry counts and sizes for one method on one chain
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MethodUsage {
+    pub chain_id: u64,
+    pub method: String,
+    pub store: String,
+    pub entries: u64,
+    pub bytes: u64,
+    pub hits: u64,
+}
+
+/// Block number and hash recorded by an indexed entry
+#[derive(Debug, Clone, Deserialize)]
+pub struct IndexedBlock {
+    pub block_number: u64,
+    pub block_hash: String,
+}
+
+/// Latest block of a chain, as recorded by the maintenance cron
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainHead {
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub updated_at_ms: u64,
+}
+
+/// Progress of a cache warming job
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WarmCheckpoint {
+    pub job: String,
+    pub chain_id: u64,
+    /// First block not warmed yet
+    pub next_block: u64,
+    pub updated_at_ms: u64,
+}
+
+/// Key and backend of an indexed entry selected for deletion
+#[derive(Debug, Clone, Deserialize)]
+pub struct IndexedKey {
+    pub key: String,
+    pub store: String,
+}
+
+/// Which entries go first when a budget is exceeded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionOrder {
+    /// Least recently hit (or stored, if never hit)
+    #[default]
+    Lru,
+    /// Lowest block first; entries without blocks go last
+    OldestBlock,
+}
+
+/// Selection of indexed entries on one chain
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexFilter {
+    pub chain_id: u64,
+    pub method: Option<String>,
+    /// Entries whose block range overlaps this one; entries without blocks never match
+    pub block_range: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SqlParam {
+    Int(u64),
+    Text(String),
+}
+
+impl SqlParam {
+    fn to_js(&self) -> JsValue {
+        match self {
+            SqlParam::Int(value) => JsValue::from_f64(*value as f64),
+            SqlParam::Text(value) => JsValue::from_str(value),
+        }
+    }
+}
+
+impl IndexFilter {
+    /// SQL condition and its positional parameters
+    fn where_clause(&self) -> (String, Vec<SqlParam>) {
+        let mut conditions = vec!["chain_id = ?1".to_string()];
+        let mut params = vec![SqlParam::Int(self.chain_id)];
+
+        if let Some(method) = &self.method {
+            params.push(SqlParam::Text(method.clone()));
+            conditions.push(format!("method = ?{}", params.len()));
+        }
+        if let Some((from_block, to_block)) = self.block_range {
+            params.push(SqlParam::Int(to_block));
+            conditions.push(format!("from_block <= ?{}", params.len()));
+            params.push(SqlParam::Int(from_block));
+            conditions.push(format!("to_block >= ?{}", params.len()));
+        }
+
+        (conditions.join(" AND "), params)
+    }
+
+    /// Like `where_clause`, but leaving out tombstoned entries, which are already on their way out
+    fn live_where_clause(&self) -> (String, Vec<SqlParam>) {
+        let (condition, params) = self.where_clause();
+        (format!("{condition} AND tombstoned_at_ms IS NULL"), params)
+    }
+}
+
+fn eviction_order_sql(order: EvictionOrder) -> &'static str {
+    match order {
+        EvictionOrder::Lru => "COALESCE(last_hit_ms, created_at_ms) ASC",
+        EvictionOrder::OldestBlock => "to_block IS NULL, to_block ASC, created_at_ms ASC",
+    }
+}
+
+fn optional_int(value: Option<u64>) -> JsValue {
+    value.map(|value| JsValue::from_f64(value as f64)).unwrap_or(JsValue::NULL)
+}
+
+/// Hit counts not yet written to the index
+#[derive(Debug, Default)]
+struct PendingHits {
+    /// Key → (hits, last hit time)
+    hits: HashMap<String, (u64, u64)>,
+    last_flush_ms: u64,
+}
+
+impl PendingHits {
+    fn record(&mut self, cache_key: &str, now: u64) {
+        if let Some((hits, last_hit_ms)) = self.hits.get_mut(cache_key) {
+            *hits += 1;
+            *last_hit_ms = now;
+        } else if self.hits.len() < MAX_PENDING_HITS {
+            self.hits.insert(cache_key.to_string(), (1, now));
+        }
+    }
+
+    fn merge(&mut self, other: HashMap<String, (u64, u64)>) {
+        for (cache_key, (hits, last_hit_ms)) in other {
+            let entry = self.hits.entry(cache_key).or_insert((0, 0));
+            entry.0 += hits;
+            entry.1 = entry.1.max(last_hit_ms);
+        }
+    }
+}
+
+thread_local! {
+    // Hits counted by this isolate since the last flush
+    static PENDING_HITS: RefCell<PendingHits> = RefCell::new(PendingHits::default());
+}
+
+/// Count a cache hit; written to the index by `flush_hits`
+pub fn record_hit(cache_key: &str) {
+    let now = Date::now().as_millis();
+    PENDING_HITS.with(|pending| pending.borrow_mut().record(cache_key, now));
+}
+
+/// Write buffered hit counts to the index, at most once per `HIT_FLUSH_INTERVAL_MS` unless forced
+pub async fn flush_hits(env: &Env, force: bool) {
+    let index = match CacheIndex::from_env(env) {
+        Some(index) => index,
+        None => return,
+    };
+
+    let now = Date::now().as_millis();
+    let pending = PENDING_HITS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        if !force && now.saturating_sub(pending.last_flush_ms) < HIT_FLUSH_INTERVAL_MS {
+            return None;
+        }
+        pending.last_flush_ms = now;
+        Some(std::mem::take(&mut pending.hits))
+    });
+
+    let pending = match pending {
+        Some(pending) if !pending.is_empty() => pending,
+        _ => return,
+    };
+
+    if let Err(e) = index.add_hits(&pending).await {
+        console_log!("ERROR: Failed to flush cache hits: {:?}", e);
+        // Keep the counts for the next flush
+        PENDING_HITS.with(|hits| hits.borrow_mut().merge(pending));
+    }
+}
+
+/// D1 index of cached entries, for range purges, reorg invalidation, storage reports
+/// and eviction
+pub struct CacheIndex {
+    db: D1Database,
+}
+
+impl CacheIndex {
+    /// The index is optional; without the binding, stores and purges work from R2 and KV alone
+    pub fn from_env(env: &Env) -> Option<Self> {
+        env.d1(INDEX_BINDING).ok().map(|db| Self { db })
+    }
+
+    /// Insert or replace the row of a stored entry, keeping its hit counts.
+    /// Storing an entry again lifts its tombstone.
+    pub async fn record_store(&self, entry: &IndexEntry) -> Result<()> {
+        self.db
+            .prepare(
+                "INSERT INTO cache_entries \
+                 (key, method, chain_id, from_block, to_block, block_hash, store, size, created_at_ms) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) \
+                 ON CONFLICT(key) DO UPDATE SET \
+                 method = excluded.method, chain_id = excluded.chain_id, \
+                 from_block = excluded.from_block, to_block = excluded.to_block, \
+                 block_hash = excluded.block_hash, store = excluded.store, \
+                 size = excluded.size, created_at_ms = excluded.created_at_ms, \
+                 tombstoned_at_ms = NULL",
+            )
+            .bind(&[
+                JsValue::from_str(&entry.key),
+                JsValue::from_str(&entry.method),
+                JsValue::from_f64(entry.chain_id as f64),
+                optional_int(entry.from_block),
+                optional_int(entry.to_block),
+                entry
+                    .block_hash
+                    .as_deref()
+                    .map(JsValue::from_str)
+                    .unwrap_or(JsValue::NULL),
+                JsValue::from_str(&entry.store),
+                JsValue::from_f64(entry.size as f64),
+                JsValue::from_f64(entry.created_at_ms as f64),
+            ])?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    async fn add_hits(&self, hits: &HashMap<String, (u64, u64)>) -> Result<()> {
+        let statements = hits
+            .iter()
+            .map(|(cache_key, (count, last_hit_ms))| {
+                self.db
+                    .prepare(
+                        "UPDATE cache_entries SET hits = hits + ?1, \
+                         last_hit_ms = MAX(COALESCE(last_hit_ms, 0), ?2) WHERE key = ?3",
+                    )
+                    .bind(&[
+                        JsValue::from_f64(*count as f64),
+                        JsValue::from_f64(*last_hit_ms as f64),
+                        JsValue::from_str(cache_key),
+                    ])
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.db.batch(statements).await?;
+        Ok(())
+    }
+
+    /// Drop the rows of deleted entries
+    pub async fn remove(&self, keys: &[String]) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let statements = keys
+            .iter()
+            .map(|cache_key| {
+                self.db
+                    .prepare("DELETE FROM cache_entries WHERE key = ?1")
+                    .bind(&[JsValue::from_str(cache_key)])
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.db.batch(statements).await?;
+        Ok(())
+    }
+
+    /// Keys matching a filter, at most `limit`
+    pub async fn find_keys(&self, filter: &IndexFilter, limit: usize) -> Result<Vec<IndexedKey>> {
+        let (condition, mut params) = filter.where_clause();
+        params.push(SqlParam::Int(limit as u64));
+        let sql = format!(
+            "SELECT key, store FROM cache_entries WHERE {condition} LIMIT ?{}",
+            params.len()
+        );
+        self.query(&sql, &params).await
+    }
+
+    /// Entries of a chain (and method) that record no blocks, so block range filters skip them
+    pub async fn count_without_blocks(&self, filter: &IndexFilter) -> Result<u64> {
+        let filter = IndexFilter {
+            block_range: None,
+            ..filter.clone()
+        };
+        let (condition, params) = filter.where_clause();
+        let sql = format!(
+            "SELECT COUNT(*) AS entries FROM cache_entries WHERE {condition} AND from_block IS NULL"
+        );
+        let js_params: Vec<JsValue> = params.iter().map(SqlParam::to_js).collect();
+        let count: Option<f64> = self.db.prepare(sql).bind(&js_params)?.first(Some("entries")).await?;
+        Ok(count.unwrap_or(0.0) as u64)
+    }
+
+    /// Total stored bytes of the entries matching a filter, tombstoned entries aside
+    pub async fn used_bytes(&self, filter: &IndexFilter) -> Result<u64> {
+        let (condition, params) = filter.live_where_clause();
+        let sql = format!("SELECT COALESCE(SUM(size), 0) AS bytes FROM cache_entries WHERE {condition}");
+        let js_params: Vec<JsValue> = params.iter().map(SqlParam::to_js).collect();
+        let bytes: Option<f64> = self.db.prepare(sql).bind(&js_params)?.first(Some("bytes")).await?;
+        Ok(bytes.unwrap_or(0.0) as u64)
+    }
+
+    /// Untombstoned entries matching a filter in the order a budget evicts them, at most `limit`
+    pub async fn eviction_candidates(
+        &self,
+        filter: &IndexFilter,
+        order: EvictionOrder,
+        limit: usize,
+    ) -> Result<Vec<IndexEntry>> {
+        let (condition, mut params) = filter.live_where_clause();
+        params.push(SqlParam::Int(limit as u64));
+        let sql = format!(
+            "SELECT * FROM cache_entries WHERE {condition} ORDER BY {} LIMIT ?{}",
+            eviction_order_sql(order),
+            params.len()
+        );
+        self.query(&sql, &params).await
+    }
+
+    /// Mark the entries matching a filter for deletion by the maintenance cron.
+    /// Returns how many were newly marked.
+    pub async fn tombstone(&self, filter: &IndexFilter, now: u64) -> Result<usize> {
+        let (condition, mut params) = filter.where_clause();
+        params.push(SqlParam::Int(now));
+        let sql = format!(
+            "UPDATE cache_entries SET tombstoned_at_ms = ?{} \
+             WHERE {condition} AND tombstoned_at_ms IS NULL",
+            params.len()
+        );
+        let js_params: Vec<JsValue> = params.iter().map(SqlParam::to_js).collect();
+        let result = self.db.prepare(sql).bind(&js_params)?.run().await?;
+        Ok(result.meta()?.and_then(|meta| meta.changes).unwrap_or(0))
+    }
+
+    /// Tombstoned entries, oldest tombstones first, at most `limit`
+    pub async fn tombstoned(&self, limit: usize) -> Result<Vec<IndexedKey>> {
+        self.query(
+            "SELECT key, store FROM cache_entries WHERE tombstoned_at_ms IS NOT NULL \
+             ORDER BY tombstoned_at_ms ASC LIMIT ?1",
+            &[SqlParam::Int(limit as u64)],
+        )
+        .await
+    }
+
+    /// The most recent distinct blocks that single-block entries of a chain recorded a hash for.
+    /// Range entries are left out: the hash they carry may belong to any block in the range.
+    pub async fn recent_blocks(&self, chain_id: u64, limit: usize) -> Result<Vec<IndexedBlock>> {
+        self.query(
+            "SELECT DISTINCT to_block AS block_number, block_hash FROM cache_entries \
+             WHERE chain_id = ?1 AND block_hash IS NOT NULL AND from_block = to_block \
+             AND tombstoned_at_ms IS NULL ORDER BY to_block DESC LIMIT ?2",
+            &[SqlParam::Int(chain_id), SqlParam::Int(limit as u64)],
+        )
+        .await
+    }
+
+    /// Record the latest block of a chain
+    pub async fn record_head(&self, chain_id: u64, block_number: u64, now: u64) -> Result<()> {
+        self.db
+            .prepare(
+                "INSERT INTO chain_heads (chain_id, block_number, updated_at_ms) VALUES (?1, ?2, ?3) \
+                 ON CONFLICT(chain_id) DO UPDATE SET \
+                 block_number = excluded.block_number, updated_at_ms = excluded.updated_at_ms",
+            )
+            .bind(&[
+                JsValue::from_f64(chain_id as f64),
+                JsValue::from_f64(block_number as f64),
+                JsValue::from_f64(now as f64),
+            ])?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    /// Latest recorded block of every chain
+    pub async fn heads(&self) -> Result<Vec<ChainHead>> {
+        self.query("SELECT * FROM chain_heads ORDER BY chain_id", &[]).await
+    }
+
+    /// Stored progress of every warming job
+    pub async fn checkpoints(&self) -> Result<Vec<WarmCheckpoint>> {
+        self.query("SELECT * FROM warm_checkpoints ORDER BY job", &[]).await
+    }
+
+    pub async fn record_checkpoint(&self, checkpoint: &WarmCheckpoint) -> Result<()> {
+        self.db
+            .prepare(
+                "INSERT INTO warm_checkpoints (job, chain_id, next_block, updated_at_ms) \
+                 VALUES (?1, ?2, ?3, ?4) ON CONFLICT(job) DO UPDATE SET \
+                 chain_id = excluded.chain_id, next_block = excluded.next_block, \
+                 updated_at_ms = excluded.updated_at_ms",
+            )
+            .bind(&[
+                JsValue::from_str(&checkpoint.job),
+                JsValue::from_f64(checkpoint.chain_id as f64),
+                JsValue::from_f64(checkpoint.next_block as f64),
+                JsValue::from_f64(checkpoint.updated_at_ms as f64),
+            ])?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    /// Entry counts, bytes and hits per chain, method and backend
+    pub async fn usage(&self, chain_id: Option<u64>) -> Result<Vec<MethodUsage>> {
+        let (condition, params) = match chain_id {
+            Some(chain_id) => ("WHERE chain_id = ?1", vec![SqlParam::Int(chain_id)]),
+            None => ("", Vec::new()),
+        };
+        let sql = format!(
+            "SELECT chain_id, method, store, COUNT(*) AS entries, \
+             COALESCE(SUM(size), 0) AS bytes, COALESCE(SUM(hits), 0) AS hits \
+             FROM cache_entries {condition} GROUP BY chain_id, method, store \
+             ORDER BY chain_id, method, store"
+        );
+        self.query(&sql, &params).await
+    }
+
+    /// Entries least recently hit (or stored, if never hit) first, for eviction
+    pub async fn least_recently_used(&self, chain_id: Option<u64>, limit: usize) -> Result<Vec<IndexEntry>> {
+        let mut params = Vec::new();
+        let condition = match chain_id {
+            Some(chain_id) => {
+                params.push(SqlParam::Int(chain_id));
+                "WHERE chain_id = ?1"
+            }
+            None => "",
+        };
+        params.push(SqlParam::Int(limit as u64));
+        let sql = format!(
+            "SELECT * FROM cache_entries {condition} ORDER BY {} LIMIT ?{}",
+            eviction_order_sql(EvictionOrder::Lru),
+            params.len()
+        );
+        self.query(&sql, &params).await
+    }
+
+    async fn query<T>(&self, sql: &str, params: &[SqlParam]) -> Result<Vec<T>>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        let js_params: Vec<JsValue> = params.iter().map(SqlParam::to_js).collect();
+        self.db.prepare(sql).bind(&js_params)?.all().await?.results()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_where_clause() {
+        let filter = IndexFilter {
+            chain_id: 1,
+            ..IndexFilter::default()
+        };
+        assert_eq!(filter.where_clause(), ("chain_id = ?1".to_string(), vec![SqlParam::Int(1)]));
+
+        let filter = IndexFilter {
+            chain_id: 137,
+            method: Some("eth_getLogs".to_string()),
+            block_range: Some((100, 200)),
+        };
+        assert_eq!(
+            filter.where_clause(),
+            (
+                "chain_id = ?1 AND method = ?2 AND from_block <= ?3 AND to_block >= ?4".to_string(),
+                vec![
+                    SqlParam::Int(137),
+                    SqlParam::Text("eth_getLogs".to_string()),
+                    SqlParam::Int(200),
+                    SqlParam::Int(100),
+                ]
+            )
+        );
+        assert_eq!(
+            filter.live_where_clause().0,
+            "chain_id = ?1 AND method = ?2 AND from_block <= ?3 AND to_block >= ?4 AND tombstoned_at_ms IS NULL"
+        );
+    }
+
+    #[test]
+    fn test_pending_hits() {
+        let mut pending = PendingHits::default();
+        pending.record("a", 10);
+        pending.record("a", 20);
+        assert_eq!(pending.hits["a"], (2, 20));
+
+        let mut failed = HashMap::new();
+        failed.insert("a".to_string(), (3, 15));
+        failed.insert("b".to_string(), (1, 5));
+        pending.merge(failed);
+        assert_eq!(pending.hits["a"], (5, 20));
+        assert_eq!(pending.hits["b"], (1, 5));
+    }
+
+    #[test]
+    fn test_pending_hits_are_bounded() {
+        let mut pending = PendingHits::default();
+        for i in 0..MAX_PENDING_HITS {
+            pending.record(&format!("key-{i}"), 0);
+        }
+        pending.record("extra", 0);
+        assert!(!pending.hits.contains_key("extra"));
+
+        // Keys already tracked keep counting
+        pending.record("key-0", 1);
+        assert_eq!(pending.hits["key-0"], (2, 1));
+    }
+}
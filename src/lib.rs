@@ -1,15 +1,47 @@
-use serde_json::{json, Value};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
 use worker::*;
 
+mod admin;
+mod auth;
+mod breaker;
+mod budget;
 mod cache;
+mod chains;
+mod config;
+mod directives;
+mod edge;
+mod envelope;
+mod handlers;
+mod index;
+mod maintenance;
+mod metrics;
+mod ratelimit;
 mod rpc;
+mod transactions;
+mod upstream;
 mod utils;
+mod warming;
 
+use admin::handle_admin;
+use auth::{authenticate, client_id, presented_key, AuthError};
 use cache::CacheManager;
-use rpc::RpcRequest;
+use chains::{redact_url, ChainConfig, ChainRegistry};
+use config::MethodPolicy;
+use directives::CacheDirectives;
+use ratelimit::{RateLimitConfig, RequestBudget};
+use handlers::{handle_cached, not_cached_response, CacheOutcome, HandlerRegistry};
+use rpc::{
+    RpcError, RpcRequest, RpcResponse, INTERNAL_ERROR, INVALID_REQUEST, LIMIT_EXCEEDED,
+    METHOD_NOT_FOUND, METHOD_NOT_SUPPORTED, RESOURCE_NOT_FOUND, RESOURCE_UNAVAILABLE,
+};
+use transactions::{broadcast_transaction, SEND_RAW_TRANSACTION};
+use upstream::{check_upstream_chain_id, fetch_upstream, forward_notification, ChainIdCheck};
+use utils::bearer_token_matches;
 
 #[event(fetch)]
-async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
+async fn main(mut req: Request, env: Env, ctx: Context) -> Result<Response> {
     console_error_panic_hook::set_once();
 
     // Handle CORS preflight
@@ -20,546 +52,441 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
             });
     }
 
-    // Extract chainId from URL path (e.g., /1, /137, /56)
-    // Takes the first path segment as chain ID, defaults to "1" if not provided
+    let registry = HandlerRegistry::new();
+
+    // Load chain configuration, reporting validation errors to the caller
+    let chain_registry = match ChainRegistry::from_env(&env, &registry.methods()) {
+        Ok(chain_registry) => chain_registry,
+        Err(e) => {
+            console_log!("ERROR: Invalid chain configuration: {:?}", e);
+            let error = RpcError::new(INTERNAL_ERROR, format!("Proxy misconfigured: {e}"));
+            return rpc_error_response(Value::Null, error, 500);
+        }
+    };
+
+    // Extract the chain from the URL path: a numeric id (/1, /137) or an alias (/eth, /polygon)
     let url = req.url()?;
     let path = url.path();
-    let chain_id = path
+
+    if req.method() == Method::Get && path == "/chains" {
+        return list_chains(&env, &chain_registry);
+    }
+
+    if path == "/admin" || path.starts_with("/admin/") {
+        return handle_admin(req, &env, &chain_registry, &registry).await;
+    }
+
+    if req.method() == Method::Get && path == "/metrics" {
+        return metrics_endpoint(&req, &env).await;
+    }
+
+    if req.method() == Method::Get && path == "/health" {
+        return health(&env, &chain_registry).await;
+    }
+
+    // Path is /{chain} or /{chain}/{api_key}
+    let mut segments = path
         .trim_start_matches('/')
         .split('/')
-        .next()
         .filter(|s| !s.is_empty())
-        .unwrap_or("1")
-        .to_string();
+        .map(|s| s.to_string());
+    let chain_segment = segments.next();
+    let path_key = segments.next();
 
-    console_log!("Request received: method={}, path={}, chain_id={}", req.method(), path, chain_id);
+    console_log!("Request received: method={}, chain={:?}", req.method(), chain_segment);
 
     // Parse the RPC request
-    let rpc_request: RpcRequest = match req.json().await {
+    let body = req.bytes().await?;
+    let rpc_request = match RpcRequest::parse(&body) {
         Ok(req) => req,
-        Err(e) => {
-            console_log!("ERROR: Failed to parse JSON-RPC request: {:?}", e);
-            return Response::error("Invalid JSON-RPC request", 400);
+        Err(error_response) => {
+            console_log!("ERROR: Invalid JSON-RPC request: {:?}", error_response.error);
+            return Response::from_json(&error_response)
+                .map(|res| res.with_status(400).with_headers(get_cors_headers()));
         }
     };
 
     console_log!("RPC request parsed: method={}, id={:?}, params={}", rpc_request.method, rpc_request.id, rpc_request.params);
 
-    // Initialize cache manager
-    let cache_manager = match CacheManager::new(&env, &chain_id) {
-        Ok(manager) => manager,
+    let key = presented_key(&req, path_key.as_deref())?;
+    let api_key = match authenticate(&env, key.as_deref()).await {
+        Ok(Ok(api_key)) => api_key,
+        Ok(Err(auth_error)) => {
+            let message = match auth_error {
+                AuthError::Missing => "API key required",
+                AuthError::Invalid => "Invalid API key",
+            };
+            let error = RpcError::new(INVALID_REQUEST, message);
+            return rpc_error_response(rpc_request.response_id(), error, 401);
+        }
         Err(e) => {
-            console_log!("ERROR: Failed to initialize cache manager: {:?}", e);
-            return Err(e);
+            console_log!("ERROR: Failed to validate API key: {:?}", e);
+            let error = RpcError::new(INTERNAL_ERROR, format!("Proxy misconfigured: {e}"));
+            return rpc_error_response(rpc_request.response_id(), error, 500);
         }
     };
 
-    // Handle different RPC methods
-    let response = match rpc_request.method.as_str() {
-        "eth_getLogs" => {
-            console_log!("Handling eth_getLogs request");
-            match handle_get_logs(&rpc_request, &cache_manager, &env, &chain_id).await {
-                Ok(resp) => resp,
-                Err(e) => {
-                    console_log!("ERROR in eth_getLogs: {:?}", e);
-                    return Err(e);
-                }
-            }
+    let mut directives = cache_directives(&req, &env, api_key.is_some())?;
+
+    // Without a chain in the path, default to mainnet unless an explicit chain is required
+    let chain_segment = match chain_segment {
+        Some(segment) => segment,
+        None if require_explicit_chain(&env) => {
+            let error = RpcError::new(
+                RESOURCE_NOT_FOUND,
+                "Chain must be specified in the path, e.g. /1 or /eth",
+            );
+            return rpc_error_response(rpc_request.response_id(), error, 404);
         }
-        "eth_getBlockByNumber" => {
-            console_log!("Handling eth_getBlockByNumber request");
-            match handle_get_block_by_number(&rpc_request, &cache_manager, &env, &chain_id).await {
-                Ok(resp) => resp,
-                Err(e) => {
-                    console_log!("ERROR in eth_getBlockByNumber: {:?}", e);
-                    return Err(e);
-                }
-            }
+        None => "1".to_string(),
+    };
+
+    let chain = match chain_registry
+        .resolve_chain_id(&chain_segment)
+        .and_then(|id| chain_registry.lookup(&env, id))
+    {
+        Some(chain) => chain,
+        None => {
+            console_log!("ERROR: Unknown chain {}", chain_segment);
+            let error = RpcError::new(RESOURCE_NOT_FOUND, format!("Unknown chain: {chain_segment}"));
+            return rpc_error_response(rpc_request.response_id(), error, 404);
         }
-        "eth_getTransactionReceipt" => {
-            console_log!("Handling eth_getTransactionReceipt request");
-            match handle_get_transaction_receipt(&rpc_request, &cache_manager, &env, &chain_id).await {
-                Ok(resp) => resp,
-                Err(e) => {
-                    console_log!("ERROR in eth_getTransactionReceipt: {:?}", e);
-                    return Err(e);
-                }
-            }
+    };
+
+    // Methods outside the chain's allowlist are answered as if the node didn't have them
+    if !chain.allows_method(&rpc_request.method) {
+        console_log!("Method {} is not allowed on chain {}", rpc_request.method, chain.id);
+        if rpc_request.is_notification() {
+            return Response::empty()
+                .map(|res| res.with_status(204).with_headers(get_cors_headers()));
         }
-        "eth_getBlockByHash" => {
-            console_log!("Handling eth_getBlockByHash request");
-            match handle_get_block_by_hash(&rpc_request, &cache_manager, &env, &chain_id).await {
-                Ok(resp) => resp,
-                Err(e) => {
-                    console_log!("ERROR in eth_getBlockByHash: {:?}", e);
-                    return Err(e);
-                }
-            }
+        let error = RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("the method {} does not exist/is not available", rpc_request.method),
+        );
+        return rpc_error_response(rpc_request.response_id(), error, 200);
+    }
+
+    // Per-key restrictions
+    if let Some(api_key) = &api_key {
+        if !api_key.allows_chain(chain.id) {
+            console_log!("API key {} may not use chain {}", api_key.label(), chain.id);
+            let error = RpcError::new(
+                RESOURCE_UNAVAILABLE,
+                format!("Chain {} is not allowed for this API key", chain.id),
+            );
+            return rpc_error_response(rpc_request.response_id(), error, 403);
         }
-        "eth_getBlockReceipts" => {
-            console_log!("Handling eth_getBlockReceipts request");
-            match handle_get_block_receipts(&rpc_request, &cache_manager, &env, &chain_id).await {
-                Ok(resp) => resp,
-                Err(e) => {
-                    console_log!("ERROR in eth_getBlockReceipts: {:?}", e);
-                    return Err(e);
-                }
-            }
+        if !api_key.allows_method(&rpc_request.method) {
+            console_log!("API key {} may not call {}", api_key.label(), rpc_request.method);
+            let error = RpcError::new(
+                METHOD_NOT_SUPPORTED,
+                format!("Method {} is not allowed for this API key", rpc_request.method),
+            );
+            return rpc_error_response(rpc_request.response_id(), error, 403);
         }
-        "debug_traceBlockByNumber" => {
-            console_log!("Handling debug_traceBlockByNumber request");
-            match handle_debug_trace_block(&rpc_request, &cache_manager, &env, &chain_id, "debug_traceBlockByNumber").await {
-                Ok(resp) => resp,
-                Err(e) => {
-                    console_log!("ERROR in debug_traceBlockByNumber: {:?}", e);
-                    return Err(e);
-                }
-            }
+        if api_key.cache_only {
+            directives.only_if_cached = true;
         }
-        "debug_traceBlockByHash" => {
-            console_log!("Handling debug_traceBlockByHash request");
-            match handle_debug_trace_block(&rpc_request, &cache_manager, &env, &chain_id, "debug_traceBlockByHash").await {
-                Ok(resp) => resp,
-                Err(e) => {
-                    console_log!("ERROR in debug_traceBlockByHash: {:?}", e);
-                    return Err(e);
-                }
-            }
+    }
+
+    // Rate limits: cached methods are admitted at the cache-hit price, misses pay the rest later
+    let rate_limits = match RateLimitConfig::from_env(&env) {
+        Ok(rate_limits) => rate_limits,
+        Err(e) => {
+            console_log!("ERROR: Invalid rate limit configuration: {:?}", e);
+            let error = RpcError::new(INTERNAL_ERROR, format!("Proxy misconfigured: {e}"));
+            return rpc_error_response(rpc_request.response_id(), error, 500);
         }
-        _ => {
-            console_log!("Proxying method: {}", rpc_request.method);
-            match proxy_request(&rpc_request, &env, &chain_id).await {
-                Ok(resp) => resp,
+    };
+    let budget = match &rate_limits {
+        Some(config) => {
+            let budget = RequestBudget::new(
+                config,
+                client_id(&req, key.as_deref().filter(|_| api_key.is_some()))?,
+                api_key.as_ref().and_then(|api_key| api_key.rate_limit),
+                &rpc_request.method,
+                registry.get(&rpc_request.method).is_some(),
+            );
+            match budget.admit(&env).await {
+                Ok(Ok(())) => Some(budget),
+                Ok(Err(retry_after_ms)) => {
+                    return rate_limited_response(rpc_request.response_id(), retry_after_ms);
+                }
                 Err(e) => {
-                    console_log!("ERROR in proxy_request: {:?}", e);
-                    return Err(e);
+                    // Fail open: a limiter outage must not take the proxy down
+                    console_log!("ERROR: Rate limiter unavailable: {:?}", e);
+                    None
                 }
             }
         }
+        None => None,
     };
 
-    console_log!("Request completed successfully for method: {}", rpc_request.method);
-    
-    Response::from_json(&response)
-        .map(|res| res.with_headers(get_cors_headers()))
-}
-
-async fn handle_get_logs(
-    rpc_request: &RpcRequest,
-    cache_manager: &CacheManager,
-    env: &Env,
-    chain_id: &str,
-) -> Result<Value> {
-    // Parse the eth_getLogs parameters
-    let params = match rpc_request.params.as_array() {
-        Some(arr) if !arr.is_empty() => &arr[0],
-        _ => {
-            return Ok(json!({
-                "jsonrpc": "2.0",
-                "id": rpc_request.id,
-                "error": {
-                    "code": -32602,
-                    "message": "Invalid params"
-                }
-            }));
-        }
-    };
-
-    // Extract block range
-    let from_block = params.get("fromBlock").and_then(|v| v.as_str());
-    let to_block = params.get("toBlock").and_then(|v| v.as_str());
-
-    // Check if we should cache this request
-    if let (Some(from), Some(to)) = (from_block, to_block) {
-        // Check if the block range is far enough from the tip to avoid reorgs
-        if let Ok(should_cache) = cache_manager.should_cache_logs(from, to, env).await {
-            if should_cache {
-                // Try to get from cache
-                if let Ok(Some(cached)) = cache_manager.get_logs_from_cache(params).await {
-                    console_log!("eth_getLogs cache HIT");
-                    return Ok(json!({
-                        "jsonrpc": "2.0",
-                        "id": rpc_request.id,
-                        "result": cached
-                    }));
-                }
-                console_log!("eth_getLogs cache MISS");
-            } else {
-                console_log!("eth_getLogs: blocks too recent, skipping cache");
-            }
+    // Cache-only requests can't reach the upstream, so uncached methods are never answered
+    if directives.only_if_cached && registry.get(&rpc_request.method).is_none() {
+        if rpc_request.is_notification() {
+            return Response::empty()
+                .map(|res| res.with_status(204).with_headers(get_cors_headers()));
         }
+        return Response::from_json(&not_cached_response(&rpc_request))
+            .map(|res| res.with_headers(get_cors_headers()));
     }
 
-    // Cache miss or not cacheable - fetch from upstream
-    let result = proxy_request(rpc_request, env, chain_id).await?;
-
-    // Store in cache if applicable
-    if let (Some(from), Some(to)) = (from_block, to_block) {
-        if let Ok(should_cache) = cache_manager.should_cache_logs(from, to, env).await {
-            if should_cache {
-                if let Some(logs) = result.get("result") {
-                    let _ = cache_manager.store_logs_in_cache(params, logs).await;
-                }
-            }
+    // Notifications expect no response: forward them upstream and answer with 204
+    if rpc_request.is_notification() {
+        console_log!("Forwarding notification: {}", rpc_request.method);
+        let forwarded = if rpc_request.method == SEND_RAW_TRANSACTION {
+            // Transactions still go to every transaction endpoint and are tracked as pending,
+            // which needs the hash from a response: submit with an id and drop the answer
+            let submission = RpcRequest {
+                id: Some(serde_json::json!(1)),
+                ..rpc_request.clone()
+            };
+            broadcast_transaction(&submission, &chain, &ctx).await.map(|_| ())
+        } else {
+            forward_notification(&rpc_request, &chain).await
+        };
+        if let Err(e) = forwarded {
+            console_log!("ERROR forwarding notification: {:?}", e);
         }
+        return Response::empty()
+            .map(|res| res.with_status(204).with_headers(get_cors_headers()));
     }
 
-    Ok(result)
-}
-
-async fn handle_get_block_by_number(
-    rpc_request: &RpcRequest,
-    cache_manager: &CacheManager,
-    env: &Env,
-    chain_id: &str,
-) -> Result<Value> {
-    // Extract block number from params
-    let block_number = match rpc_request.params.as_array() {
-        Some(arr) if !arr.is_empty() => arr[0].as_str().unwrap_or("latest"),
-        _ => "latest",
+    // Initialize cache manager
+    let cache_manager = match CacheManager::new(&env, &chain) {
+        Ok(manager) => manager,
+        Err(e) => {
+            console_log!("ERROR: Failed to initialize cache manager: {:?}", e);
+            let error = RpcError::new(INTERNAL_ERROR, format!("Proxy misconfigured: {e}"));
+            return rpc_error_response(rpc_request.response_id(), error, 500);
+        }
     };
 
-    // Try to get from in-memory cache (2 second TTL)
-    if let Some(cached) = cache_manager.get_block_from_cache(block_number) {
-        console_log!("eth_getBlockByNumber cache HIT for block {}", block_number);
-        return Ok(json!({
-            "jsonrpc": "2.0",
-            "id": rpc_request.id,
-            "result": cached
-        }));
-    }
-
-    console_log!("eth_getBlockByNumber cache MISS for block {}", block_number);
-
-    // Fetch from upstream
-    let result = proxy_request(rpc_request, env, chain_id).await?;
-
-    // Store in memory cache with 2 second TTL
-    if let Some(block) = result.get("result") {
-        cache_manager.store_block_in_cache(block_number, block);
-    }
-
-    Ok(result)
-}
-
-async fn handle_get_transaction_receipt(
-    rpc_request: &RpcRequest,
-    cache_manager: &CacheManager,
-    env: &Env,
-    chain_id: &str,
-) -> Result<Value> {
-    // Extract transaction hash from params
-    let tx_hash = match rpc_request.params.as_array() {
-        Some(arr) if !arr.is_empty() => {
-            arr[0].as_str().ok_or("Transaction hash must be a string")?
+    // Cached methods are dispatched through the handler registry, everything else is proxied
+    let response = match registry.get(&rpc_request.method) {
+        Some(handler) => {
+            console_log!("Handling {} request", rpc_request.method);
+            handle_cached(handler, &rpc_request, &cache_manager, directives).await
         }
-        _ => {
-            return Ok(json!({
-                "jsonrpc": "2.0",
-                "id": rpc_request.id,
-                "error": {
-                    "code": -32602,
-                    "message": "Invalid params: missing transaction hash"
-                }
-            }));
+        // Transactions go to the dedicated submission endpoints and are never cached
+        None if rpc_request.method == SEND_RAW_TRANSACTION => {
+            console_log!("Broadcasting transaction on chain {}", chain.id);
+            broadcast_transaction(&rpc_request, &chain, &ctx)
+                .await
+                .map(|upstream_response| {
+                    let outcome = CacheOutcome::bypass().with_upstream(&upstream_response);
+                    (upstream_response.response, outcome)
+                })
+        }
+        None => {
+            console_log!("Proxying method: {}", rpc_request.method);
+            fetch_upstream(&rpc_request, &chain).await.map(|upstream_response| {
+                let outcome = CacheOutcome::bypass().with_upstream(&upstream_response);
+                (upstream_response.response, outcome)
+            })
         }
     };
 
-    // Try to get from R2 cache
-    if let Ok(Some(cached)) = cache_manager.get_tx_receipt_from_cache(tx_hash).await {
-        console_log!("eth_getTransactionReceipt cache HIT for tx {}", tx_hash);
-        return Ok(json!({
-            "jsonrpc": "2.0",
-            "id": rpc_request.id,
-            "result": cached
-        }));
-    }
+    let (response, outcome) = match response {
+        Ok(resp) => resp,
+        Err(e) => {
+            console_log!("ERROR in {}: {:?}", rpc_request.method, e);
+            return Err(e);
+        }
+    };
 
-    console_log!("eth_getTransactionReceipt cache MISS for tx {}", tx_hash);
+    console_log!("Request completed successfully for method: {}", rpc_request.method);
 
-    // Fetch from upstream
-    let result = proxy_request(rpc_request, env, chain_id).await?;
+    if let Some(budget) = &budget {
+        budget.settle(&env, outcome.upstream.is_some()).await;
+    }
 
-    // Store in R2 cache if receipt is confirmed (has blockNumber)
-    if let Some(receipt) = result.get("result") {
-        if cache_manager.should_cache_tx_receipt(receipt) {
-            console_log!("Transaction receipt is confirmed, storing in cache");
-            let _ = cache_manager.store_tx_receipt_in_cache(tx_hash, receipt).await;
-        } else {
-            console_log!("Transaction receipt not confirmed yet, skipping cache");
-        }
+    // Migrate entries read in an older format, write edge cache copies, push this isolate's
+    // counters to the aggregator and its hit counts to the index after the response is sent
+    let flush_env = env.clone();
+    ctx.wait_until(async move {
+        cache_manager.flush_deferred_writes().await;
+        metrics::flush(&flush_env, false).await;
+        index::flush_hits(&flush_env, false).await;
+    });
+
+    let mut headers = get_cors_headers();
+    for (name, value) in outcome.headers() {
+        headers.set(name, &value)?;
     }
+    Response::from_json(&response).map(|res| res.with_headers(headers))
+}
 
-    Ok(result)
+/// Effective configuration of a chain, as listed by `/chains`
+#[derive(Serialize)]
+struct ChainSummary {
+    #[serde(flatten)]
+    chain: ChainConfig,
+    methods: BTreeMap<&'static str, MethodPolicy>,
 }
 
-async fn handle_get_block_by_hash(
-    rpc_request: &RpcRequest,
-    cache_manager: &CacheManager,
-    env: &Env,
-    chain_id: &str,
-) -> Result<Value> {
-    // Extract block hash from params
-    let block_hash = match rpc_request.params.as_array() {
-        Some(arr) if !arr.is_empty() => {
-            arr[0].as_str().ok_or("Block hash must be a string")?
-        }
-        _ => {
-            return Ok(json!({
-                "jsonrpc": "2.0",
-                "id": rpc_request.id,
-                "error": {
-                    "code": -32602,
-                    "message": "Invalid params: missing block hash"
-                }
-            }));
-        }
-    };
+/// List every configured chain with its effective per-method policy. Upstream URLs are redacted.
+fn list_chains(env: &Env, chain_registry: &ChainRegistry) -> Result<Response> {
+    let registry = HandlerRegistry::new();
+    let mut summaries = Vec::new();
+
+    for chain in chain_registry.list(env) {
+        let cache_manager = match CacheManager::new(env, &chain) {
+            Ok(manager) => manager,
+            Err(e) => {
+                let error = RpcError::new(INTERNAL_ERROR, format!("Proxy misconfigured: {e}"));
+                return rpc_error_response(Value::Null, error, 500);
+            }
+        };
+
+        let methods = registry
+            .methods()
+            .into_iter()
+            .map(|method| (method, cache_manager.method_policy(method)))
+            .collect();
 
-    // Try to get from R2 cache
-    if let Ok(Some(cached)) = cache_manager.get_block_by_hash_from_cache(block_hash).await {
-        console_log!("eth_getBlockByHash cache HIT for block {}", block_hash);
-        return Ok(json!({
-            "jsonrpc": "2.0",
-            "id": rpc_request.id,
-            "result": cached
-        }));
+        summaries.push(ChainSummary { chain, methods });
     }
 
-    console_log!("eth_getBlockByHash cache MISS for block {}", block_hash);
+    Response::from_json(&summaries).map(|res| res.with_headers(get_cors_headers()))
+}
 
-    // Fetch from upstream
-    let result = proxy_request(rpc_request, env, chain_id).await?;
+/// Cron trigger entry point: runs cache maintenance (see `maintenance::run`)
+#[event(scheduled)]
+async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    console_error_panic_hook::set_once();
+    maintenance::run(&env, &event.cron()).await;
+}
 
-    // Store in R2 cache if block is old enough
-    if let Some(block) = result.get("result") {
-        if !block.is_null() {
-            if let Ok(should_cache) = cache_manager.should_cache_block(block, env).await {
-                if should_cache {
-                    console_log!("Block is old enough, storing in cache");
-                    let _ = cache_manager.store_block_by_hash_in_cache(block_hash, block).await;
-                } else {
-                    console_log!("Block is too recent, skipping cache");
-                }
-            }
-        }
-    }
+#[derive(Serialize)]
+struct UpstreamHealth {
+    url: String,
+    #[serde(flatten)]
+    check: ChainIdCheck,
+}
 
-    Ok(result)
+#[derive(Serialize)]
+struct ChainHealth {
+    id: u64,
+    name: String,
+    upstreams: Vec<UpstreamHealth>,
 }
 
-async fn handle_get_block_receipts(
-    rpc_request: &RpcRequest,
-    cache_manager: &CacheManager,
-    env: &Env,
-    chain_id: &str,
-) -> Result<Value> {
-    // Extract block identifier from params (can be block number or hash)
-    let block_id = match rpc_request.params.as_array() {
-        Some(arr) if !arr.is_empty() => {
-            arr[0].as_str().ok_or("Block identifier must be a string")?
-        }
-        _ => {
-            return Ok(json!({
-                "jsonrpc": "2.0",
-                "id": rpc_request.id,
-                "error": {
-                    "code": -32602,
-                    "message": "Invalid params: missing block identifier"
-                }
-            }));
+/// Report whether each upstream serves the chain it is configured for.
+/// Any mismatched or unreachable upstream makes the proxy report itself as degraded (503).
+async fn health(env: &Env, chain_registry: &ChainRegistry) -> Result<Response> {
+    let mut chains = Vec::new();
+    let mut healthy = true;
+
+    for chain in chain_registry.list(env) {
+        let mut upstreams = Vec::new();
+        for upstream_url in &chain.upstreams {
+            let check = check_upstream_chain_id(upstream_url, chain.id).await;
+            healthy &= check == ChainIdCheck::Ok;
+            upstreams.push(UpstreamHealth {
+                url: redact_url(upstream_url),
+                check,
+            });
         }
-    };
 
-    // Detect if it's a block hash (66 chars) or block number
-    let is_block_hash = block_id.starts_with("0x") && block_id.len() == 66;
-
-    // Try to get from R2 cache
-    if let Ok(Some(cached)) = cache_manager.get_block_receipts_from_cache(block_id).await {
-        console_log!("eth_getBlockReceipts cache HIT for block {}", block_id);
-        return Ok(json!({
-            "jsonrpc": "2.0",
-            "id": rpc_request.id,
-            "result": cached
-        }));
+        chains.push(ChainHealth {
+            id: chain.id,
+            name: chain.name.clone(),
+            upstreams,
+        });
     }
 
-    console_log!("eth_getBlockReceipts cache MISS for block {}", block_id);
-
-    // Fetch from upstream
-    let result = proxy_request(rpc_request, env, chain_id).await?;
-
-    // Store in R2 cache if block is old enough
-    if let Some(receipts) = result.get("result") {
-        if !receipts.is_null() {
-            // For block hash, check block number from response
-            // For block number, check directly
-            let should_cache = if is_block_hash {
-                console_log!("Block hash provided - checking block number from response");
-                // Try to extract block number from first receipt
-                if let Some(receipts_array) = receipts.as_array() {
-                    if let Some(first_receipt) = receipts_array.first() {
-                        cache_manager.should_cache_from_response(first_receipt, env).await.unwrap_or(false)
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                }
-            } else {
-                cache_manager.should_cache_block_id(block_id, env).await.unwrap_or(false)
-            };
+    let body = serde_json::json!({
+        "status": if healthy { "ok" } else { "degraded" },
+        "chains": chains,
+    });
 
-            if should_cache {
-                console_log!("Block receipts are for old block, storing in cache");
-                let _ = cache_manager.store_block_receipts_in_cache(block_id, receipts).await;
-            } else {
-                console_log!("Block is too recent or no block number found, skipping cache");
-            }
+    Response::from_json(&body)
+        .map(|res| res.with_status(if healthy { 200 } else { 503 }).with_headers(get_cors_headers()))
+}
+
+/// Counters in Prometheus text format. Requires `Authorization: Bearer <METRICS_TOKEN>` when set.
+async fn metrics_endpoint(req: &Request, env: &Env) -> Result<Response> {
+    if let Ok(token) = env.secret("METRICS_TOKEN") {
+        let authorization = req.headers().get("Authorization")?;
+        if !bearer_token_matches(authorization.as_deref(), &token.to_string()) {
+            return Response::error("Unauthorized", 401);
         }
     }
 
-    Ok(result)
+    let body = metrics::snapshot(env).await.render();
+    let mut headers = get_cors_headers();
+    headers.set("Content-Type", "text/plain; version=0.0.4")?;
+    Response::ok(body).map(|res| res.with_headers(headers))
 }
 
-async fn handle_debug_trace_block(
-    rpc_request: &RpcRequest,
-    cache_manager: &CacheManager,
-    env: &Env,
-    chain_id: &str,
-    method: &str,
-) -> Result<Value> {
-    // Extract block identifier from params (can be block number or hash)
-    let block_id = match rpc_request.params.as_array() {
-        Some(arr) if !arr.is_empty() => {
-            arr[0].as_str().ok_or("Block identifier must be a string")?
-        }
-        _ => {
-            return Ok(json!({
-                "jsonrpc": "2.0",
-                "id": rpc_request.id,
-                "error": {
-                    "code": -32602,
-                    "message": "Invalid params: missing block identifier"
-                }
-            }));
-        }
-    };
-
-    // Detect if it's a block hash (66 chars) or block number
-    let is_block_hash = block_id.starts_with("0x") && block_id.len() == 66;
-
-    // Try to get from R2 cache
-    if let Ok(Some(cached)) = cache_manager.get_trace_from_cache(method, block_id).await {
-        console_log!("{} cache HIT for block {}", method, block_id);
-        return Ok(json!({
-            "jsonrpc": "2.0",
-            "id": rpc_request.id,
-            "result": cached
-        }));
+/// Cache directives sent by the client. With `CACHE_DIRECTIVES_REQUIRE_AUTH = "true"` they are
+/// only honored for requests with a valid API key or `Authorization: Bearer <ADMIN_TOKEN>`.
+fn cache_directives(req: &Request, env: &Env, has_api_key: bool) -> Result<CacheDirectives> {
+    let directives = CacheDirectives::from_request(req)?;
+    if directives.is_empty() || has_api_key {
+        return Ok(directives);
     }
 
-    console_log!("{} cache MISS for block {}", method, block_id);
-
-    // Fetch from upstream
-    let result = proxy_request(rpc_request, env, chain_id).await?;
-
-    // Store in R2 cache if block is old enough
-    if let Some(trace) = result.get("result") {
-        if !trace.is_null() {
-            // For block hash, check block number from response
-            // For block number, check directly
-            let should_cache = if is_block_hash {
-                console_log!("Block hash provided - checking block number from response");
-                // Debug traces might have block info at different locations
-                // Try to extract from trace structure
-                if let Some(block_obj) = trace.as_object() {
-                    // Look for block number in trace result
-                    if let Some(_struct_logs) = block_obj.get("structLogs") {
-                        // It's a transaction trace, might not have block number directly
-                        // For now, don't cache block hash traces unless we can extract block number
-                        console_log!("Debug trace by hash - cannot determine block age, skipping cache");
-                        false
-                    } else {
-                        // Try direct check
-                        cache_manager.should_cache_from_response(trace, env).await.unwrap_or(false)
-                    }
-                } else {
-                    false
-                }
-            } else {
-                cache_manager.should_cache_block_id(block_id, env).await.unwrap_or(false)
-            };
-
-            if should_cache {
-                console_log!("Block trace is for old block, storing in cache");
-                let _ = cache_manager.store_trace_in_cache(method, block_id, trace).await;
-            } else {
-                console_log!("Block is too recent or cannot determine age, skipping cache");
-            }
-        }
+    let require_auth = env
+        .var("CACHE_DIRECTIVES_REQUIRE_AUTH")
+        .map(|v| v.to_string() == "true")
+        .unwrap_or(false);
+    if !require_auth {
+        return Ok(directives);
     }
 
-    Ok(result)
+    let authorization = req.headers().get("Authorization")?;
+    let authorized = env
+        .secret("ADMIN_TOKEN")
+        .map(|token| bearer_token_matches(authorization.as_deref(), &token.to_string()))
+        .unwrap_or(false);
+    if authorized {
+        Ok(directives)
+    } else {
+        console_log!("Ignoring cache directives from unauthenticated request: {:?}", directives);
+        // only-if-cached only restricts the request, so it is always honored
+        Ok(CacheDirectives {
+            only_if_cached: directives.only_if_cached,
+            ..CacheDirectives::default()
+        })
+    }
 }
 
-async fn proxy_request(rpc_request: &RpcRequest, env: &Env, chain_id: &str) -> Result<Value> {
-    let upstream_url = env
-        .var(&format!("UPSTREAM_RPC_URL_{}", chain_id))?
-        .to_string();
-
-    console_log!("Proxying to upstream: {}", upstream_url);
-
-    let mut headers = Headers::new();
-    headers.set("Content-Type", "application/json")?;
-
-    let request_body = match serde_json::to_string(rpc_request) {
-        Ok(body) => body,
-        Err(e) => {
-            console_log!("ERROR: Failed to serialize RPC request: {:?}", e);
-            return Err(e.to_string().into());
-        }
-    };
-
-    console_log!("Upstream request body: {}", request_body);
-
-    let request = Request::new_with_init(
-        &upstream_url,
-        RequestInit::new()
-            .with_method(Method::Post)
-            .with_headers(headers)
-            .with_body(Some(request_body.into())),
-    )?;
-
-    let mut response = match Fetch::Request(request).send().await {
-        Ok(resp) => resp,
-        Err(e) => {
-            console_log!("ERROR: Failed to send request to upstream: {:?}", e);
-            return Err(e);
-        }
-    };
+/// `REQUIRE_EXPLICIT_CHAIN = "true"` rejects requests without a chain in the path
+fn require_explicit_chain(env: &Env) -> bool {
+    env.var("REQUIRE_EXPLICIT_CHAIN")
+        .map(|v| v.to_string() == "true")
+        .unwrap_or(false)
+}
 
-    let status = response.status_code();
-    console_log!("Upstream response status: {}", status);
+/// JSON-RPC -32005 with HTTP 429 and a `Retry-After` hint
+fn rate_limited_response(id: Value, retry_after_ms: u64) -> Result<Response> {
+    let error = RpcError::new(LIMIT_EXCEEDED, "Rate limit exceeded")
+        .with_data(&serde_json::json!({ "retry_after_ms": retry_after_ms }));
 
-    let response_json: Value = match response.json().await {
-        Ok(json) => json,
-        Err(e) => {
-            console_log!("ERROR: Failed to parse upstream response as JSON: {:?}", e);
-            return Err(e);
-        }
-    };
-
-    console_log!("Upstream response: {}", response_json);
+    let mut headers = get_cors_headers();
+    headers.set("Retry-After", &retry_after_ms.div_ceil(1000).to_string())?;
+    Response::from_json(&RpcResponse::error(id, error))
+        .map(|res| res.with_status(429).with_headers(headers))
+}
 
-    Ok(response_json)
+fn rpc_error_response(id: Value, error: RpcError, status: u16) -> Result<Response> {
+    Response::from_json(&RpcResponse::error(id, error))
+        .map(|res| res.with_status(status).with_headers(get_cors_headers()))
 }
 
 fn get_cors_headers() -> Headers {
     let mut headers = Headers::new();
     let _ = headers.set("Access-Control-Allow-Origin", "*");
     let _ = headers.set("Access-Control-Allow-Methods", "GET, POST, OPTIONS");
-    let _ = headers.set("Access-Control-Allow-Headers", "Content-Type");
+    let _ = headers.set("Access-Control-Allow-Headers", "Content-Type, Authorization, X-API-Key, X-Cache-Control");
+    let _ = headers.set(
+        "Access-Control-Expose-Headers",
+        "X-Cache, X-Cache-Key, X-Cache-Tier, X-Upstream, X-Upstream-Latency-Ms",
+    );
     headers
 }
 
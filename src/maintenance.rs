@@ -0,0 +1,269 @@
+use serde_json::{json, Value};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use worker::*;
+
+use crate::budget::{enforce_budgets, BudgetConfig};
+use crate::cache::CacheManager;
+use crate::chains::{ChainConfig, ChainRegistry};
+use crate::handlers::HandlerRegistry;
+use crate::index::{CacheIndex, IndexedBlock};
+use crate::rpc::RpcRequest;
+use crate::upstream::proxy_request;
+use crate::warming::{warm, WarmingConfig};
+
+/// Cron expression whose runs also log the daily cache summary
+const DAILY_SUMMARY_CRON: &str = "0 0 * * *";
+/// Most recent indexed blocks compared against the canonical chain, per chain and run
+const MAX_REORG_CHECKS: usize = 16;
+/// Upper bound on deletes per run, for reorg invalidation and tombstones alike
+const MAX_MAINTENANCE_DELETES: usize = 500;
+
+/// Cache maintenance run by the cron triggers: refresh chain heads, check recent blocks for
+/// reorgs, delete tombstoned entries, warm configured ranges, enforce storage budgets and, on
+/// the daily trigger, log a summary. Each task logs its own failures so one failing task doesn't stop the others.
+pub async fn run(env: &Env, cron: &str) {
+    console_log!("Running cache maintenance for cron \"{}\"", cron);
+
+    let registry = HandlerRegistry::new();
+    let chain_registry = match ChainRegistry::from_env(env, &registry.methods()) {
+        Ok(chain_registry) => chain_registry,
+        Err(e) => {
+            console_log!("ERROR: Maintenance skipped, invalid chain configuration: {:?}", e);
+            return;
+        }
+    };
+    let chains = chain_registry.list(env);
+    let index = CacheIndex::from_env(env);
+
+    for chain in &chains {
+        let cache_manager = match CacheManager::new(env, chain) {
+            Ok(cache_manager) => cache_manager,
+            Err(e) => {
+                console_log!("ERROR: Maintenance skipped for chain {}: {:?}", chain.id, e);
+                continue;
+            }
+        };
+
+        let head = match refresh_head(&cache_manager, index.as_ref()).await {
+            Ok(head) => head,
+            Err(e) => {
+                console_log!("ERROR: Failed to refresh head of chain {}: {:?}", chain.id, e);
+                continue;
+            }
+        };
+
+        if let Some(index) = &index {
+            if let Err(e) = check_reorgs(&cache_manager, index, head).await {
+                console_log!("ERROR: Reorg check failed for chain {}: {:?}", chain.id, e);
+            }
+        }
+    }
+
+    if let (Some(index), Some(chain)) = (&index, chains.first()) {
+        if let Err(e) = delete_tombstoned(env, chain, index).await {
+            console_log!("ERROR: Failed to delete tombstoned entries: {:?}", e);
+        }
+    }
+
+    if let Err(e) = run_warming(env, &chain_registry, &registry).await {
+        console_log!("ERROR: Cache warming failed: {:?}", e);
+    }
+
+    if let Err(e) = run_budgets(env, &chain_registry, &registry).await {
+        console_log!("ERROR: Budget enforcement failed: {:?}", e);
+    }
+
+    if cron == DAILY_SUMMARY_CRON {
+        match &index {
+            Some(index) => {
+                if let Err(e) = log_summary(index).await {
+                    console_log!("ERROR: Failed to build the daily summary: {:?}", e);
+                }
+            }
+            None => console_log!("Daily summary skipped: no CACHE_INDEX binding"),
+        }
+    }
+}
+
+/// Fetch the chain tip and record it in the index
+async fn refresh_head(cache_manager: &CacheManager, index: Option<&CacheIndex>) -> Result<u64> {
+    let head = cache_manager.get_current_block_number().await?;
+    if let Some(index) = index {
+        let chain_id = cache_manager.chain().id;
+        index.record_head(chain_id, head, Date::now().as_millis()).await?;
+    }
+    Ok(head)
+}
+
+/// Compare the hashes recorded by the most recent indexed blocks with the canonical chain and
+/// invalidate everything from the lowest block that no longer matches
+async fn check_reorgs(cache_manager: &CacheManager, index: &CacheIndex, head: u64) -> Result<()> {
+    let chain = cache_manager.chain();
+    let blocks: Vec<IndexedBlock> = index
+        .recent_blocks(chain.id, MAX_REORG_CHECKS)
+        .await?
+        .into_iter()
+        .filter(|block| block.block_number <= head)
+        .collect();
+
+    let mut canonical = BTreeMap::new();
+    for block in &blocks {
+        if let Entry::Vacant(entry) = canonical.entry(block.block_number) {
+            entry.insert(canonical_block_hash(chain, block.block_number).await?);
+        }
+    }
+
+    let reorged_from = match first_reorged_block(&blocks, &canonical) {
+        Some(block_number) => block_number,
+        None => return Ok(()),
+    };
+
+    console_log!(
+        "Reorg detected on chain {} at block {}, invalidating",
+        chain.id,
+        reorged_from
+    );
+    if let Some(stats) = cache_manager
+        .invalidate_from_block(reorged_from, MAX_MAINTENANCE_DELETES)
+        .await?
+    {
+        console_log!(
+            "Reorg on chain {}: deleted {} entries, {} left tombstoned",
+            chain.id,
+            stats.deleted,
+            stats.tombstoned
+        );
+    }
+    Ok(())
+}
+
+/// Lowest block whose recorded hash differs from the canonical one. Blocks the upstream
+/// doesn't know (yet) are not treated as reorged.
+fn first_reorged_block(blocks: &[IndexedBlock], canonical: &BTreeMap<u64, Option<String>>) -> Option<u64> {
+    blocks
+        .iter()
+        .filter(|block| match canonical.get(&block.block_number) {
+            Some(Some(hash)) => !hash.eq_ignore_ascii_case(&block.block_hash),
+            _ => false,
+        })
+        .map(|block| block.block_number)
+        .min()
+}
+
+async fn canonical_block_hash(chain: &ChainConfig, block_number: u64) -> Result<Option<String>> {
+    let rpc_request = RpcRequest {
+        jsonrpc: "2.0".to_string(),
+        method: "eth_getBlockByNumber".to_string(),
+        params: json!([format!("0x{block_number:x}"), false]),
+        id: Some(json!(1)),
+    };
+
+    let response = proxy_request(&rpc_request, chain).await?;
+    Ok(response
+        .result
+        .as_ref()
+        .and_then(|block| block.get("hash"))
+        .and_then(Value::as_str)
+        .map(str::to_string))
+}
+
+/// Delete entries tombstoned by reorg invalidations that exceeded their delete limit.
+/// Storage bindings are shared by all chains, so any chain's cache manager will do.
+async fn delete_tombstoned(env: &Env, chain: &ChainConfig, index: &CacheIndex) -> Result<()> {
+    let entries = index.tombstoned(MAX_MAINTENANCE_DELETES).await?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let deleted = CacheManager::new(env, chain)?.delete_indexed(entries).await?;
+    console_log!("Deleted {} tombstoned entries", deleted);
+    Ok(())
+}
+
+/// Continue the `CACHE_WARMING` jobs from their checkpoints
+async fn run_warming(env: &Env, chain_registry: &ChainRegistry, registry: &HandlerRegistry) -> Result<()> {
+    let config = WarmingConfig::from_env(env, chain_registry)?;
+    if config.jobs.is_empty() {
+        return Ok(());
+    }
+
+    warm(env, chain_registry, registry, &config, None, false).await?;
+    Ok(())
+}
+
+/// Enforce `CACHE_BUDGETS`, honoring its `dry_run` flag
+async fn run_budgets(env: &Env, chain_registry: &ChainRegistry, registry: &HandlerRegistry) -> Result<()> {
+    let config = BudgetConfig::from_env(env, &registry.methods())?;
+    if config.chains.is_empty() {
+        return Ok(());
+    }
+
+    let reports = enforce_budgets(env, chain_registry, &config, config.dry_run).await?;
+    for report in reports.iter().filter(|report| !report.evicted.is_empty()) {
+        let keys: Vec<&str> = report.evicted.iter().map(|eviction| eviction.key.as_str()).collect();
+        console_log!(
+            "Budget chain {} {}{}: {}",
+            report.chain_id,
+            report.method,
+            if report.dry_run { " (dry run)" } else { "" },
+            keys.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Log stored entries, bytes and hits per chain and method, with each chain's head
+async fn log_summary(index: &CacheIndex) -> Result<()> {
+    let heads = index.heads().await?;
+    let usage = index.usage(None).await?;
+
+    console_log!(
+        "Daily cache summary: {} entries, {} bytes, {} hits",
+        usage.iter().map(|row| row.entries).sum::<u64>(),
+        usage.iter().map(|row| row.bytes).sum::<u64>(),
+        usage.iter().map(|row| row.hits).sum::<u64>()
+    );
+    for head in &heads {
+        console_log!("  chain {}: head {}", head.chain_id, head.block_number);
+    }
+    for row in &usage {
+        console_log!(
+            "  chain {} {} ({}): {} entries, {} bytes, {} hits",
+            row.chain_id,
+            row.method,
+            row.store,
+            row.entries,
+            row.bytes,
+            row.hits
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(block_number: u64, block_hash: &str) -> IndexedBlock {
+        IndexedBlock {
+            block_number,
+            block_hash: block_hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_first_reorged_block() {
+        let blocks = vec![block(105, "0xE"), block(104, "0xd"), block(103, "0xc"), block(102, "0xb")];
+        let mut canonical = BTreeMap::new();
+        canonical.insert(105, Some("0xe".to_string()));
+        canonical.insert(104, Some("0xd".to_string()));
+        canonical.insert(103, Some("0xc".to_string()));
+        canonical.insert(102, None);
+        assert_eq!(first_reorged_block(&blocks, &canonical), None);
+
+        canonical.insert(104, Some("0xd2".to_string()));
+        canonical.insert(103, Some("0xc2".to_string()));
+        assert_eq!(first_reorged_block(&blocks, &canonical), Some(103));
+    }
+}
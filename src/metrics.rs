@@ -0,0 +1,380 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use worker::*;
+
+/// Durable Object binding that aggregates counters across isolates
+const AGGREGATOR_BINDING: &str = "METRICS";
+const AGGREGATOR_NAME: &str = "global";
+const AGGREGATOR_STORAGE_KEY: &str = "series";
+
+/// Minimum time between flushes of pending counters to the aggregator
+const FLUSH_INTERVAL_MS: u64 = 10_000;
+
+/// Cap on distinct series kept per isolate; further methods are counted as "other"
+const MAX_SERIES: usize = 2_000;
+
+/// A counted event, labelled by chain and method
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    CacheHit,
+    CacheMiss,
+    CacheBypass,
+    CacheStore,
+    CacheStoreFailure,
+    CacheSkippedRecent,
+    CacheMigrated,
+    CacheCorrupt,
+    CacheReadFailure,
+    CacheDegraded,
+    CacheEdgeHit,
+    CacheKvHit,
+    UpstreamError,
+    UpstreamRpcError,
+    /// Summary: recorded with `record_latency`
+    UpstreamLatencyMs,
+}
+
+impl Metric {
+    const ALL: [Metric; 15] = [
+        Metric::CacheHit,
+        Metric::CacheMiss,
+        Metric::CacheBypass,
+        Metric::CacheStore,
+        Metric::CacheStoreFailure,
+        Metric::CacheSkippedRecent,
+        Metric::CacheMigrated,
+        Metric::CacheCorrupt,
+        Metric::CacheReadFailure,
+        Metric::CacheDegraded,
+        Metric::CacheEdgeHit,
+        Metric::CacheKvHit,
+        Metric::UpstreamError,
+        Metric::UpstreamRpcError,
+        Metric::UpstreamLatencyMs,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Metric::CacheHit => "rpc_cache_hits_total",
+            Metric::CacheMiss => "rpc_cache_misses_total",
+            Metric::CacheBypass => "rpc_cache_bypasses_total",
+            Metric::CacheStore => "rpc_cache_stores_total",
+            Metric::CacheStoreFailure => "rpc_cache_store_failures_total",
+            Metric::CacheSkippedRecent => "rpc_cache_skipped_recent_total",
+            Metric::CacheMigrated => "rpc_cache_migrations_total",
+            Metric::CacheCorrupt => "rpc_cache_corrupt_entries_total",
+            Metric::CacheReadFailure => "rpc_cache_read_failures_total",
+            Metric::CacheDegraded => "rpc_cache_degraded_total",
+            Metric::CacheEdgeHit => "rpc_cache_edge_hits_total",
+            Metric::CacheKvHit => "rpc_cache_kv_hits_total",
+            Metric::UpstreamError => "rpc_upstream_errors_total",
+            Metric::UpstreamRpcError => "rpc_upstream_rpc_errors_total",
+            Metric::UpstreamLatencyMs => "rpc_upstream_latency_ms",
+        }
+    }
+
+    fn help(self) -> &'static str {
+        match self {
+            Metric::CacheHit => "Requests served from the cache",
+            Metric::CacheMiss => "Cached-method requests fetched from upstream after a cache lookup",
+            Metric::CacheBypass => "Cached-method requests fetched from upstream without a cache lookup",
+            Metric::CacheStore => "Results written to the cache",
+            Metric::CacheStoreFailure => "Failed cache writes",
+            Metric::CacheSkippedRecent => "Results not cached because they are not final yet",
+            Metric::CacheMigrated => "R2 entries rewritten in the current storage format on read",
+            Metric::CacheCorrupt => "R2 entries that failed to decode or verify and were deleted",
+            Metric::CacheReadFailure => "Cache reads that failed and were treated as misses",
+            Metric::CacheDegraded => "Requests proxied without the cache because its storage was unavailable",
+            Metric::CacheEdgeHit => "R2-tier hits served from the edge cache without reading R2",
+            Metric::CacheKvHit => "R2-tier hits served from KV",
+            Metric::UpstreamError => "Upstream requests that failed at the transport level",
+            Metric::UpstreamRpcError => "Upstream responses carrying a JSON-RPC error",
+            Metric::UpstreamLatencyMs => "Upstream request latency in milliseconds",
+        }
+    }
+
+    fn kind(self) -> &'static str {
+        match self {
+            Metric::UpstreamLatencyMs => "summary",
+            _ => "counter",
+        }
+    }
+}
+
+/// Counter values keyed by Prometheus series, e.g. `rpc_cache_hits_total{chain="1",method="eth_getLogs"}`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    series: BTreeMap<String, u64>,
+}
+
+impl MetricsSnapshot {
+    fn add(&mut self, series: String, amount: u64) {
+        *self.series.entry(series).or_insert(0) += amount;
+    }
+
+    fn merge(&mut self, other: MetricsSnapshot) {
+        for (series, value) in other.series {
+            self.add(series, value);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+
+    /// Render in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        for metric in Metric::ALL {
+            let name = metric.name();
+            output.push_str(&format!("# HELP {} {}\n", name, metric.help()));
+            output.push_str(&format!("# TYPE {} {}\n", name, metric.kind()));
+
+            let prefixes = match metric.kind() {
+                "summary" => vec![format!("{name}_sum{{"), format!("{name}_count{{")],
+                _ => vec![format!("{name}{{")],
+            };
+            for prefix in &prefixes {
+                for (series, value) in self.series.range(prefix.clone()..) {
+                    if !series.starts_with(prefix.as_str()) {
+                        break;
+                    }
+                    output.push_str(&format!("{series} {value}\n"));
+                }
+            }
+        }
+
+        output
+    }
+}
+
+#[derive(Default)]
+struct LocalMetrics {
+    /// Everything recorded by this isolate, served when no aggregator is bound
+    totals: MetricsSnapshot,
+    /// Recorded since the last flush to the aggregator
+    pending: MetricsSnapshot,
+    last_flush_ms: u64,
+}
+
+thread_local! {
+    static LOCAL_METRICS: RefCell<LocalMetrics> = RefCell::new(LocalMetrics::default());
+}
+
+/// Count one event for a chain and method
+pub fn record(metric: Metric, chain: &str, method: &str) {
+    record_series(metric.name(), chain, method, 1);
+}
+
+/// Record one upstream round trip
+pub fn record_latency(chain: &str, method: &str, latency_ms: u64) {
+    let name = Metric::UpstreamLatencyMs.name();
+    record_series(&format!("{name}_sum"), chain, method, latency_ms);
+    record_series(&format!("{name}_count"), chain, method, 1);
+}
+
+fn record_series(name: &str, chain: &str, method: &str, amount: u64) {
+    LOCAL_METRICS.with(|metrics| {
+        let mut metrics = metrics.borrow_mut();
+
+        let mut series = series_key(name, chain, method_label(method));
+        if metrics.totals.series.len() >= MAX_SERIES && !metrics.totals.series.contains_key(&series) {
+            series = series_key(name, chain, "other");
+        }
+
+        metrics.totals.add(series.clone(), amount);
+        metrics.pending.add(series, amount);
+    });
+}
+
+fn series_key(name: &str, chain: &str, method: &str) -> String {
+    format!(
+        "{}{{chain=\"{}\",method=\"{}\"}}",
+        name,
+        escape_label(chain),
+        escape_label(method)
+    )
+}
+
+/// Method names come from clients, so anything that doesn't look like an RPC method is grouped
+fn method_label(method: &str) -> &str {
+    let valid = !method.is_empty()
+        && method.len() <= 64
+        && method.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        method
+    } else {
+        "other"
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Send pending counters to the aggregator, at most once per `FLUSH_INTERVAL_MS` unless forced.
+/// Without an aggregator binding, pending counters are dropped and only isolate totals remain.
+pub async fn flush(env: &Env, force: bool) {
+    let now = Date::now().as_millis();
+
+    let pending = LOCAL_METRICS.with(|metrics| {
+        let mut metrics = metrics.borrow_mut();
+        if !force && now.saturating_sub(metrics.last_flush_ms) < FLUSH_INTERVAL_MS {
+            return None;
+        }
+        metrics.last_flush_ms = now;
+        Some(std::mem::take(&mut metrics.pending))
+    });
+
+    let pending = match pending {
+        Some(pending) if !pending.is_empty() => pending,
+        _ => return,
+    };
+
+    let stub = match aggregator_stub(env) {
+        Some(stub) => stub,
+        None => return,
+    };
+
+    if let Err(e) = send_to_aggregator(&stub, &pending).await {
+        console_log!("ERROR: Failed to flush metrics: {:?}", e);
+        // Keep the counters for the next flush
+        LOCAL_METRICS.with(|metrics| metrics.borrow_mut().pending.merge(pending));
+    }
+}
+
+async fn send_to_aggregator(stub: &Stub, snapshot: &MetricsSnapshot) -> Result<()> {
+    let request = Request::new_with_init(
+        "https://metrics/record",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(snapshot)?.into())),
+    )?;
+
+    let response = stub.fetch_with_request(request).await?;
+    if response.status_code() != 204 {
+        return Err(format!("aggregator returned status {}", response.status_code()).into());
+    }
+    Ok(())
+}
+
+fn aggregator_stub(env: &Env) -> Option<Stub> {
+    let namespace = env.durable_object(AGGREGATOR_BINDING).ok()?;
+    let id = namespace.id_from_name(AGGREGATOR_NAME).ok()?;
+    id.get_stub().ok()
+}
+
+/// Current counters: aggregated across isolates when the aggregator is bound,
+/// otherwise this isolate's totals
+pub async fn snapshot(env: &Env) -> MetricsSnapshot {
+    if let Some(stub) = aggregator_stub(env) {
+        flush(env, true).await;
+
+        match fetch_aggregated(&stub).await {
+            Ok(snapshot) => return snapshot,
+            Err(e) => console_log!("ERROR: Failed to read aggregated metrics: {:?}", e),
+        }
+    }
+
+    LOCAL_METRICS.with(|metrics| metrics.borrow().totals.clone())
+}
+
+async fn fetch_aggregated(stub: &Stub) -> Result<MetricsSnapshot> {
+    let mut response = stub.fetch_with_str("https://metrics/snapshot").await?;
+    response.json().await
+}
+
+/// Aggregates counters flushed by every isolate
+#[durable_object]
+pub struct MetricsAggregator {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for MetricsAggregator {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let mut storage = self.state.storage();
+        let mut totals: MetricsSnapshot = storage
+            .get(AGGREGATOR_STORAGE_KEY)
+            .await
+            .unwrap_or_default();
+
+        match (req.method(), req.path().as_str()) {
+            (Method::Post, "/record") => {
+                let delta: MetricsSnapshot = req.json().await?;
+                totals.merge(delta);
+                storage.put(AGGREGATOR_STORAGE_KEY, &totals).await?;
+                Response::empty().map(|res| res.with_status(204))
+            }
+            (Method::Get, "/snapshot") => Response::from_json(&totals),
+            _ => Response::error("Not found", 404),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_label_sanitization() {
+        assert_eq!(method_label("eth_getLogs"), "eth_getLogs");
+        assert_eq!(method_label("eth_getLogs\"} 1\n"), "other");
+        assert_eq!(method_label(""), "other");
+        assert_eq!(method_label(&"a".repeat(65)), "other");
+    }
+
+    #[test]
+    fn test_series_key_escapes_labels() {
+        assert_eq!(
+            series_key("rpc_cache_hits_total", "1", "eth_getLogs"),
+            r#"rpc_cache_hits_total{chain="1",method="eth_getLogs"}"#
+        );
+        assert_eq!(
+            series_key("x", "a\"b", "c\\d"),
+            r#"x{chain="a\"b",method="c\\d"}"#
+        );
+    }
+
+    #[test]
+    fn test_snapshot_merge() {
+        let mut a = MetricsSnapshot::default();
+        a.add(series_key("rpc_cache_hits_total", "1", "eth_getLogs"), 2);
+
+        let mut b = MetricsSnapshot::default();
+        b.add(series_key("rpc_cache_hits_total", "1", "eth_getLogs"), 3);
+        b.add(series_key("rpc_cache_misses_total", "1", "eth_getLogs"), 1);
+
+        a.merge(b);
+        assert_eq!(a.series.len(), 2);
+        assert_eq!(a.series[&series_key("rpc_cache_hits_total", "1", "eth_getLogs")], 5);
+    }
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let mut snapshot = MetricsSnapshot::default();
+        snapshot.add(series_key("rpc_cache_store_failures_total", "1", "eth_getLogs"), 1);
+        snapshot.add(series_key("rpc_cache_stores_total", "1", "eth_getLogs"), 4);
+        snapshot.add(series_key("rpc_upstream_latency_ms_sum", "137", "eth_call"), 250);
+        snapshot.add(series_key("rpc_upstream_latency_ms_count", "137", "eth_call"), 2);
+
+        let text = snapshot.render();
+        assert!(text.contains("# TYPE rpc_cache_stores_total counter\n"));
+        assert!(text.contains("rpc_cache_stores_total{chain=\"1\",method=\"eth_getLogs\"} 4\n"));
+        assert!(text.contains("# TYPE rpc_upstream_latency_ms summary\n"));
+        assert!(text.contains("rpc_upstream_latency_ms_sum{chain=\"137\",method=\"eth_call\"} 250\n"));
+        assert!(text.contains("rpc_upstream_latency_ms_count{chain=\"137\",method=\"eth_call\"} 2\n"));
+
+        // Each series appears exactly once, under its own family
+        assert_eq!(text.matches("rpc_cache_store_failures_total{").count(), 1);
+    }
+}
@@ -0,0 +1,397 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use worker::*;
+
+use crate::utils::glob_match;
+
+/// Durable Object binding that keeps buckets consistent across isolates
+const LIMITER_BINDING: &str = "RATE_LIMITER";
+
+/// Per-isolate buckets are dropped after this long without use (they are full again by then)
+const IDLE_BUCKET_MS: u64 = 10 * 60 * 1000;
+
+/// Rate limits loaded from the `RATE_LIMITS` variable:
+///
+/// ```json
+/// {
+///   "capacity": 1000,
+///   "refill_per_second": 50,
+///   "weights": { "eth_chainId": 1, "eth_getLogs": 20, "debug_trace*": 100 },
+///   "hit_factor": 0.1
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub capacity: f64,
+    pub refill_per_second: f64,
+    /// Cost of methods not listed in `weights`
+    #[serde(default = "default_weight")]
+    pub default_weight: f64,
+    /// Method name or `*` pattern → cost. Exact names win, then the longest matching pattern.
+    #[serde(default)]
+    pub weights: BTreeMap<String, f64>,
+    /// Fraction of the method weight charged for cache hits
+    #[serde(default = "default_hit_factor")]
+    pub hit_factor: f64,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+fn default_hit_factor() -> f64 {
+    0.1
+}
+
+/// Size and refill rate of a token bucket; API keys may override the configured default
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketLimit {
+    pub capacity: f64,
+    pub refill_per_second: f64,
+}
+
+impl BucketLimit {
+    /// Buckets must hold and refill tokens, or retry hints would be infinite
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.capacity <= 0.0 || self.refill_per_second <= 0.0 {
+            Err("capacity and refill_per_second must be greater than 0".to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Load from `RATE_LIMITS`; unset means no rate limiting
+    pub fn from_env(env: &Env) -> Result<Option<Self>> {
+        match env.var("RATE_LIMITS") {
+            Ok(raw) => Self::parse(&raw.to_string()),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn parse(raw: &str) -> Result<Option<Self>> {
+        if raw.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let config: Self = serde_json::from_str(raw)
+            .map_err(|e| Error::RustError(format!("Invalid RATE_LIMITS: {e}")))?;
+
+        let mut errors = Vec::new();
+        if let Err(e) = config.limit().validate() {
+            errors.push(e);
+        }
+        if !(0.0..=1.0).contains(&config.hit_factor) {
+            errors.push("hit_factor must be between 0 and 1".to_string());
+        }
+        for (pattern, weight) in &config.weights {
+            if *weight < 0.0 {
+                errors.push(format!("weights.{pattern}: must not be negative"));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Some(config))
+        } else {
+            Err(Error::RustError(format!("Invalid RATE_LIMITS: {}", errors.join("; "))))
+        }
+    }
+
+    /// Bucket used for clients without a per-key limit
+    pub fn limit(&self) -> BucketLimit {
+        BucketLimit {
+            capacity: self.capacity,
+            refill_per_second: self.refill_per_second,
+        }
+    }
+
+    /// Cost of one call to `method`
+    pub fn weight(&self, method: &str) -> f64 {
+        if let Some(weight) = self.weights.get(method) {
+            return *weight;
+        }
+
+        self.weights
+            .iter()
+            .filter(|(pattern, _)| pattern.contains('*') && glob_match(pattern, method))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, weight)| *weight)
+            .unwrap_or(self.default_weight)
+    }
+}
+
+/// Token bucket; tokens may go negative when a request turns out to cost more than admitted
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated_ms: u64,
+}
+
+impl TokenBucket {
+    pub fn full(limit: BucketLimit, now: u64) -> Self {
+        Self {
+            tokens: limit.capacity,
+            updated_ms: now,
+        }
+    }
+
+    fn refill(&mut self, limit: BucketLimit, now: u64) {
+        let elapsed_seconds = now.saturating_sub(self.updated_ms) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed_seconds * limit.refill_per_second).min(limit.capacity);
+        self.updated_ms = now;
+    }
+
+    /// Take `cost` tokens, or return how many milliseconds until they are available.
+    /// A cost above capacity could never be admitted, so it is capped at a full bucket.
+    pub fn try_take(&mut self, limit: BucketLimit, cost: f64, now: u64) -> std::result::Result<(), u64> {
+        self.refill(limit, now);
+        let cost = cost.min(limit.capacity);
+
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            return Ok(());
+        }
+
+        let missing = cost - self.tokens;
+        Err((missing / limit.refill_per_second * 1000.0).ceil() as u64)
+    }
+
+    /// Take `cost` tokens unconditionally
+    pub fn charge(&mut self, limit: BucketLimit, cost: f64, now: u64) {
+        self.refill(limit, now);
+        self.tokens -= cost;
+    }
+}
+
+thread_local! {
+    static LOCAL_BUCKETS: RefCell<HashMap<String, TokenBucket>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BucketOp {
+    Take,
+    Charge,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BucketRequest {
+    op: BucketOp,
+    cost: f64,
+    limit: BucketLimit,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BucketResponse {
+    retry_after_ms: Option<u64>,
+}
+
+/// The rate limit charge for one request. Cached methods are admitted at the cache-hit price
+/// and charged the rest of their weight once the response turns out not to be a hit.
+pub struct RequestBudget {
+    client: String,
+    limit: BucketLimit,
+    admit_cost: f64,
+    miss_surcharge: f64,
+}
+
+impl RequestBudget {
+    pub fn new(
+        config: &RateLimitConfig,
+        client: String,
+        key_limit: Option<BucketLimit>,
+        method: &str,
+        cached_method: bool,
+    ) -> Self {
+        let weight = config.weight(method);
+        let admit_cost = if cached_method {
+            weight * config.hit_factor
+        } else {
+            weight
+        };
+
+        Self {
+            client,
+            limit: key_limit.unwrap_or_else(|| config.limit()),
+            admit_cost,
+            miss_surcharge: weight - admit_cost,
+        }
+    }
+
+    /// Take the admission cost; `Ok(Err(retry_after_ms))` when the client is over its limit
+    pub async fn admit(&self, env: &Env) -> Result<std::result::Result<(), u64>> {
+        let retry_after_ms = self.apply(env, BucketOp::Take, self.admit_cost).await?;
+        Ok(match retry_after_ms {
+            Some(retry_after_ms) => Err(retry_after_ms),
+            None => Ok(()),
+        })
+    }
+
+    /// Charge the remaining weight for requests that had to reach the upstream
+    pub async fn settle(&self, env: &Env, reached_upstream: bool) {
+        if !reached_upstream || self.miss_surcharge <= 0.0 {
+            return;
+        }
+        if let Err(e) = self.apply(env, BucketOp::Charge, self.miss_surcharge).await {
+            console_log!("ERROR: Failed to charge rate limit for {}: {:?}", self.client, e);
+        }
+    }
+
+    async fn apply(&self, env: &Env, op: BucketOp, cost: f64) -> Result<Option<u64>> {
+        if let Ok(namespace) = env.durable_object(LIMITER_BINDING) {
+            let stub = namespace.id_from_name(&self.client)?.get_stub()?;
+            let body = BucketRequest {
+                op,
+                cost,
+                limit: self.limit,
+            };
+            let request = Request::new_with_init(
+                "https://rate-limiter/apply",
+                RequestInit::new()
+                    .with_method(Method::Post)
+                    .with_body(Some(serde_json::to_string(&body)?.into())),
+            )?;
+            let response: BucketResponse = stub.fetch_with_request(request).await?.json().await?;
+            return Ok(response.retry_after_ms);
+        }
+
+        let now = Date::now().as_millis();
+        Ok(LOCAL_BUCKETS.with(|buckets| {
+            let mut buckets = buckets.borrow_mut();
+            buckets.retain(|_, bucket| now.saturating_sub(bucket.updated_ms) < IDLE_BUCKET_MS);
+
+            let bucket = buckets
+                .entry(self.client.clone())
+                .or_insert_with(|| TokenBucket::full(self.limit, now));
+            apply_op(bucket, op, cost, self.limit, now)
+        }))
+    }
+}
+
+fn apply_op(bucket: &mut TokenBucket, op: BucketOp, cost: f64, limit: BucketLimit, now: u64) -> Option<u64> {
+    match op {
+        BucketOp::Take => bucket.try_take(limit, cost, now).err(),
+        BucketOp::Charge => {
+            bucket.charge(limit, cost, now);
+            None
+        }
+    }
+}
+
+/// Holds the bucket for one client. State lives in memory: if the object is evicted the
+/// bucket simply starts full again.
+#[durable_object]
+pub struct RateLimiter {
+    bucket: Option<TokenBucket>,
+}
+
+#[durable_object]
+impl DurableObject for RateLimiter {
+    fn new(state: State, _env: Env) -> Self {
+        // Buckets are kept in memory only, so the storage API is not needed
+        drop(state);
+        Self { bucket: None }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let request: BucketRequest = req.json().await?;
+        let now = Date::now().as_millis();
+
+        let bucket = self
+            .bucket
+            .get_or_insert_with(|| TokenBucket::full(request.limit, now));
+        let retry_after_ms = apply_op(bucket, request.op, request.cost, request.limit, now);
+
+        Response::from_json(&BucketResponse { retry_after_ms })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: BucketLimit = BucketLimit {
+        capacity: 100.0,
+        refill_per_second: 10.0,
+    };
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig::parse(
+            r#"{
+                "capacity": 100,
+                "refill_per_second": 10,
+                "weights": {"eth_chainId": 1, "debug_trace*": 100, "debug_*": 50, "eth_getLogs": 20}
+            }"#,
+        )
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_method_weights() {
+        let config = config();
+        assert_eq!(config.weight("eth_chainId"), 1.0);
+        assert_eq!(config.weight("debug_traceBlockByNumber"), 100.0);
+        assert_eq!(config.weight("debug_getRawBlock"), 50.0);
+        assert_eq!(config.weight("eth_call"), 1.0);
+    }
+
+    #[test]
+    fn test_hits_are_cheaper() {
+        let config = config();
+        let cached = RequestBudget::new(&config, "c".to_string(), None, "eth_getLogs", true);
+        assert_eq!(cached.admit_cost, 2.0);
+        assert_eq!(cached.miss_surcharge, 18.0);
+
+        let uncached = RequestBudget::new(&config, "c".to_string(), None, "eth_call", false);
+        assert_eq!(uncached.admit_cost, 1.0);
+        assert_eq!(uncached.miss_surcharge, 0.0);
+    }
+
+    #[test]
+    fn test_bucket_take_and_refill() {
+        let mut bucket = TokenBucket::full(LIMIT, 0);
+
+        assert_eq!(bucket.try_take(LIMIT, 100.0, 0), Ok(()));
+        assert_eq!(bucket.try_take(LIMIT, 10.0, 0), Err(1000));
+
+        // One second refills 10 tokens
+        assert_eq!(bucket.try_take(LIMIT, 10.0, 1000), Ok(()));
+
+        // Refill never exceeds capacity
+        bucket.refill(LIMIT, 1_000_000);
+        assert_eq!(bucket.tokens, 100.0);
+    }
+
+    #[test]
+    fn test_weight_above_capacity_is_admitted() {
+        let mut bucket = TokenBucket::full(LIMIT, 0);
+
+        // A full bucket admits it, taking every token
+        assert_eq!(bucket.try_take(LIMIT, 250.0, 0), Ok(()));
+        assert_eq!(bucket.tokens, 0.0);
+
+        // The next one waits for the bucket to refill, not for 250 tokens
+        assert_eq!(bucket.try_take(LIMIT, 250.0, 0), Err(10_000));
+        assert_eq!(bucket.try_take(LIMIT, 250.0, 10_000), Ok(()));
+    }
+
+    #[test]
+    fn test_charge_can_go_negative() {
+        let mut bucket = TokenBucket::full(LIMIT, 0);
+        bucket.charge(LIMIT, 150.0, 0);
+        assert_eq!(bucket.try_take(LIMIT, 1.0, 0), Err(5100));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(RateLimitConfig::parse("").unwrap().is_none());
+        assert!(RateLimitConfig::parse(r#"{"capacity": 0, "refill_per_second": 1}"#).is_err());
+        assert!(RateLimitConfig::parse(r#"{"capacity": 1, "refill_per_second": 1, "hit_factor": 2}"#).is_err());
+        assert!(RateLimitConfig::parse(r#"{"capacity": 1, "refill_per_second": 1, "weight": {}}"#).is_err());
+    }
+}
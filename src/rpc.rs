@@ -1,32 +1,303 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
 use serde_json::Value;
 
+/// Standard JSON-RPC 2.0 error codes
+pub const PARSE_ERROR: i32 = -32700;
+pub const INVALID_REQUEST: i32 = -32600;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+pub const INTERNAL_ERROR: i32 = -32603;
+
+/// EIP-1474 server error codes
+pub const RESOURCE_NOT_FOUND: i32 = -32001;
+pub const RESOURCE_UNAVAILABLE: i32 = -32002;
+pub const METHOD_NOT_SUPPORTED: i32 = -32004;
+pub const LIMIT_EXCEEDED: i32 = -32005;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RpcRequest {
     pub jsonrpc: String,
     pub method: String,
     #[serde(default = "default_params")]
     pub params: Value,
-    pub id: Value,
+    /// `None` for notifications (requests without an `id` member)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
 }
 
 fn default_params() -> Value {
     serde_json::json!([])
 }
 
+impl RpcRequest {
+    /// Parse a raw request body following the JSON-RPC 2.0 spec.
+    /// On failure returns the error response that should be sent back to the client.
+    pub fn parse(body: &[u8]) -> std::result::Result<Self, Box<RpcResponse>> {
+        let value: Value = serde_json::from_slice(body).map_err(|e| {
+            Box::new(RpcResponse::error(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, format!("Parse error: {e}")),
+            ))
+        })?;
+
+        Self::from_value(value)
+    }
+
+    /// Validate an already-parsed JSON value as a single JSON-RPC request
+    pub fn from_value(value: Value) -> std::result::Result<Self, Box<RpcResponse>> {
+        let obj = match value {
+            Value::Object(obj) => obj,
+            Value::Array(_) => {
+                return Err(invalid_request(Value::Null, "batch requests are not supported"));
+            }
+            _ => return Err(invalid_request(Value::Null, "request must be an object")),
+        };
+
+        // The id is echoed back in error responses, but only if it is a valid id
+        let id = match obj.get("id") {
+            None => None,
+            Some(id @ (Value::Null | Value::String(_) | Value::Number(_))) => Some(id.clone()),
+            Some(_) => {
+                return Err(invalid_request(Value::Null, "id must be a string, number or null"));
+            }
+        };
+        let error_id = id.clone().unwrap_or(Value::Null);
+
+        match obj.get("jsonrpc").and_then(|v| v.as_str()) {
+            Some("2.0") => {}
+            _ => return Err(invalid_request(error_id, "jsonrpc must be \"2.0\"")),
+        }
+
+        let method = match obj.get("method").and_then(|v| v.as_str()) {
+            Some(method) if !method.is_empty() => method.to_string(),
+            _ => return Err(invalid_request(error_id, "method must be a non-empty string")),
+        };
+
+        let params = match obj.get("params") {
+            None => default_params(),
+            Some(params @ (Value::Array(_) | Value::Object(_))) => params.clone(),
+            Some(_) => return Err(invalid_request(error_id, "params must be an array or object")),
+        };
+
+        Ok(Self {
+            jsonrpc: "2.0".to_string(),
+            method,
+            params,
+            id,
+        })
+    }
+
+    /// Notifications have no `id` and must not be answered
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+
+    /// The id to echo back in the response
+    pub fn response_id(&self) -> Value {
+        self.id.clone().unwrap_or(Value::Null)
+    }
+}
+
+fn invalid_request(id: Value, reason: &str) -> Box<RpcResponse> {
+    Box::new(RpcResponse::error(
+        id,
+        RpcError::new(INVALID_REQUEST, format!("Invalid Request: {reason}")),
+    ))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RpcResponse {
+    #[serde(default = "default_jsonrpc")]
     pub jsonrpc: String,
+    #[serde(default)]
     pub id: Value,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// `Some(Value::Null)` is a valid result (e.g. an unknown transaction receipt),
+    /// so a present `null` must not collapse into `None`
+    #[serde(
+        default,
+        deserialize_with = "deserialize_present",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub result: Option<Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<RpcError>,
 }
 
+fn default_jsonrpc() -> String {
+    "2.0".to_string()
+}
+
+fn deserialize_present<'de, D>(deserializer: D) -> std::result::Result<Option<Value>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Value::deserialize(deserializer).map(Some)
+}
+
+impl RpcResponse {
+    pub fn success(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    pub fn error(id: Value, error: RpcError) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RpcError {
     pub code: i32,
     pub message: String,
+    /// Kept as raw JSON so upstream error data (e.g. revert data) is passed through unchanged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Box<RawValue>>,
+}
+
+impl RpcError {
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    /// Attach structured error data
+    pub fn with_data(mut self, data: &Value) -> Self {
+        self.data = serde_json::value::to_raw_value(data).ok();
+        self
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn error_code(result: std::result::Result<RpcRequest, Box<RpcResponse>>) -> i32 {
+        result.unwrap_err().error.unwrap().code
+    }
+
+    #[test]
+    fn test_parse_valid_request() {
+        let body = br#"{"jsonrpc":"2.0","method":"eth_blockNumber","id":1}"#;
+        let request = RpcRequest::parse(body).unwrap();
+
+        assert_eq!(request.method, "eth_blockNumber");
+        assert_eq!(request.params, json!([]));
+        assert_eq!(request.id, Some(json!(1)));
+        assert!(!request.is_notification());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let response = RpcRequest::parse(b"{not json").unwrap_err();
+
+        assert_eq!(response.id, Value::Null);
+        assert_eq!(response.error.unwrap().code, PARSE_ERROR);
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        assert_eq!(error_code(RpcRequest::parse(b"42")), INVALID_REQUEST);
+        assert_eq!(error_code(RpcRequest::parse(b"[]")), INVALID_REQUEST);
+        assert_eq!(
+            error_code(RpcRequest::parse(br#"[{"jsonrpc":"2.0","method":"eth_chainId","id":1}]"#)),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            error_code(RpcRequest::parse(br#"{"method":"eth_chainId","id":1}"#)),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            error_code(RpcRequest::parse(br#"{"jsonrpc":"1.0","method":"eth_chainId","id":1}"#)),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            error_code(RpcRequest::parse(br#"{"jsonrpc":"2.0","id":1}"#)),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            error_code(RpcRequest::parse(br#"{"jsonrpc":"2.0","method":"eth_chainId","params":"x","id":1}"#)),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            error_code(RpcRequest::parse(br#"{"jsonrpc":"2.0","method":"eth_chainId","id":{}}"#)),
+            INVALID_REQUEST
+        );
+    }
+
+    #[test]
+    fn test_invalid_request_echoes_id() {
+        let response = RpcRequest::parse(br#"{"jsonrpc":"1.0","method":"eth_chainId","id":"abc"}"#).unwrap_err();
+        assert_eq!(response.id, json!("abc"));
+    }
+
+    #[test]
+    fn test_notification() {
+        let request = RpcRequest::parse(br#"{"jsonrpc":"2.0","method":"eth_chainId"}"#).unwrap();
+        assert!(request.is_notification());
+
+        // Notifications are forwarded without an id member
+        let serialized = serde_json::to_value(&request).unwrap();
+        assert!(serialized.get("id").is_none());
+    }
+
+    #[test]
+    fn test_null_id_is_not_a_notification() {
+        let request = RpcRequest::parse(br#"{"jsonrpc":"2.0","method":"eth_chainId","id":null}"#).unwrap();
+        assert!(!request.is_notification());
+        assert_eq!(request.id, Some(Value::Null));
+    }
+
+    #[test]
+    fn test_null_result_is_preserved() {
+        let response: RpcResponse = serde_json::from_str(r#"{"jsonrpc":"2.0","id":1,"result":null}"#).unwrap();
+        assert_eq!(response.result, Some(Value::Null));
+
+        let serialized = serde_json::to_string(&response).unwrap();
+        assert_eq!(serialized, r#"{"jsonrpc":"2.0","id":1,"result":null}"#);
+    }
+
+    #[test]
+    fn test_upstream_error_data_is_preserved() {
+        let upstream = r#"{"jsonrpc":"2.0","id":7,"error":{"code":3,"message":"execution reverted","data":"0x08c379a0"}}"#;
+        let response: RpcResponse = serde_json::from_str(upstream).unwrap();
+        assert!(response.result.is_none());
+        assert_eq!(serde_json::to_string(&response).unwrap(), upstream);
+
+        // Structured data keeps its exact formatting, including number precision
+        let upstream = r#"{"code":-32000,"message":"err","data":{"gas": 1.000000000000000000001}}"#;
+        let error: RpcError = serde_json::from_str(upstream).unwrap();
+        assert_eq!(error.data.unwrap().get(), r#"{"gas": 1.000000000000000000001}"#);
+    }
+
+    #[test]
+    fn test_error_with_data() {
+        let error = RpcError::new(LIMIT_EXCEEDED, "Limit exceeded").with_data(&json!({"retry_after_ms": 1500}));
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({"code": -32005, "message": "Limit exceeded", "data": {"retry_after_ms": 1500}})
+        );
+    }
+
+    #[test]
+    fn test_success_response_shape() {
+        let response = RpcResponse::success(json!("a"), json!("0x1"));
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({"jsonrpc": "2.0", "id": "a", "result": "0x1"})
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use worker::*;
+
+use crate::chains::{redact_url, ChainConfig};
+use crate::metrics::{self, Metric};
+use crate::rpc::{RpcRequest, RpcResponse};
+use crate::upstream::{send_to_upstream, UpstreamResponse};
+
+/// Method routed to the transaction submission path
+pub const SEND_RAW_TRANSACTION: &str = "eth_sendRawTransaction";
+
+/// How long a submitted transaction is treated as pending if no mined receipt is seen
+const PENDING_TTL_MS: u64 = 30 * 60 * 1000;
+/// Upper bound on tracked transactions per isolate
+const MAX_PENDING: usize = 10_000;
+
+/// Transactions submitted through this proxy whose receipts have not been seen mined yet
+#[derive(Debug, Default)]
+struct PendingTransactions {
+    submitted_ms: HashMap<String, u64>,
+}
+
+impl PendingTransactions {
+    fn insert(&mut self, tx_hash: &str, now: u64) {
+        if self.submitted_ms.len() >= MAX_PENDING {
+            self.submitted_ms
+                .retain(|_, submitted| now.saturating_sub(*submitted) < PENDING_TTL_MS);
+        }
+        if self.submitted_ms.len() < MAX_PENDING {
+            self.submitted_ms.insert(tx_hash.to_ascii_lowercase(), now);
+        }
+    }
+
+    fn contains(&self, tx_hash: &str, now: u64) -> bool {
+        self.submitted_ms
+            .get(&tx_hash.to_ascii_lowercase())
+            .is_some_and(|submitted| now.saturating_sub(*submitted) < PENDING_TTL_MS)
+    }
+
+    fn remove(&mut self, tx_hash: &str) {
+        self.submitted_ms.remove(&tx_hash.to_ascii_lowercase());
+    }
+}
+
+thread_local! {
+    // Transactions broadcast by this isolate, so their receipts bypass the cache until mined
+    static PENDING_TRANSACTIONS: RefCell<PendingTransactions> = RefCell::new(PendingTransactions::default());
+}
+
+/// Whether a transaction was submitted here and has not been seen mined yet
+pub fn is_pending(tx_hash: &str) -> bool {
+    let now = Date::now().as_millis();
+    PENDING_TRANSACTIONS.with(|pending| pending.borrow().contains(tx_hash, now))
+}
+
+/// Stop tracking a transaction once a mined receipt for it has been stored
+pub fn mark_mined(tx_hash: &str) {
+    PENDING_TRANSACTIONS.with(|pending| pending.borrow_mut().remove(tx_hash));
+}
+
+/// Broadcast `eth_sendRawTransaction` to every transaction endpoint of the chain at once and
+/// answer with the first success. The remaining submissions finish in the background.
+/// If every endpoint fails, the first JSON-RPC error (e.g. "nonce too low") is returned.
+pub async fn broadcast_transaction(
+    rpc_request: &RpcRequest,
+    chain: &ChainConfig,
+    ctx: &Context,
+) -> Result<UpstreamResponse> {
+    let request_body = serde_json::to_string(rpc_request)?;
+    let chain_label = chain.id_str();
+
+    let mut submissions: FuturesUnordered<_> = chain
+        .tx_endpoints()
+        .iter()
+        .map(|endpoint| {
+            let endpoint = endpoint.clone();
+            let request_body = request_body.clone();
+            async move {
+                let started_ms = Date::now().as_millis();
+                let result = send_to_upstream(&endpoint, &request_body).await;
+                let latency_ms = Date::now().as_millis().saturating_sub(started_ms);
+                (endpoint, result, latency_ms)
+            }
+        })
+        .collect();
+
+    let mut rpc_error = None;
+    let mut last_error: Error = "No transaction endpoint configured".into();
+
+    while let Some((endpoint, result, latency_ms)) = submissions.next().await {
+        metrics::record_latency(&chain_label, SEND_RAW_TRANSACTION, latency_ms);
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                console_log!("ERROR: Transaction endpoint {} failed: {:?}", redact_url(&endpoint), e);
+                metrics::record(Metric::UpstreamError, &chain_label, SEND_RAW_TRANSACTION);
+                last_error = e;
+                continue;
+            }
+        };
+
+        let rejected = response.error.is_some();
+        if rejected {
+            console_log!(
+                "Transaction endpoint {} rejected submission: {:?}",
+                redact_url(&endpoint),
+                response.error
+            );
+            metrics::record(Metric::UpstreamRpcError, &chain_label, SEND_RAW_TRANSACTION);
+        }
+
+        let upstream_response = UpstreamResponse {
+            response,
+            upstream: Some(endpoint),
+            // Submission results are never cached, so verification doesn't matter here
+            chain_verified: false,
+            latency_ms: Some(latency_ms),
+        };
+        if rejected {
+            rpc_error.get_or_insert(upstream_response);
+            continue;
+        }
+
+        if let Some(tx_hash) = upstream_response.response.result.as_ref().and_then(|v| v.as_str()) {
+            console_log!("Transaction {} accepted on chain {}", tx_hash, chain.id);
+            let now = Date::now().as_millis();
+            PENDING_TRANSACTIONS.with(|pending| pending.borrow_mut().insert(tx_hash, now));
+        }
+
+        if !submissions.is_empty() {
+            ctx.wait_until(finish_submissions(submissions, chain_label));
+        }
+        return Ok(upstream_response);
+    }
+
+    match rpc_error {
+        Some(upstream_response) => Ok(upstream_response),
+        None => Err(last_error),
+    }
+}
+
+/// Let the slower endpoints receive the transaction after the client has its answer
+async fn finish_submissions<F>(mut submissions: FuturesUnordered<F>, chain_label: String)
+where
+    F: std::future::Future<Output = (String, Result<RpcResponse>, u64)>,
+{
+    while let Some((endpoint, result, latency_ms)) = submissions.next().await {
+        metrics::record_latency(&chain_label, SEND_RAW_TRANSACTION, latency_ms);
+        match result {
+            Ok(response) if response.error.is_some() => {
+                console_log!(
+                    "Transaction endpoint {} rejected submission: {:?}",
+                    redact_url(&endpoint),
+                    response.error
+                );
+            }
+            Ok(_) => {}
+            Err(e) => {
+                console_log!("ERROR: Transaction endpoint {} failed: {:?}", redact_url(&endpoint), e);
+                metrics::record(Metric::UpstreamError, &chain_label, SEND_RAW_TRANSACTION);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0xABCdef0000000000000000000000000000000000000000000000000000000001";
+
+    #[test]
+    fn test_pending_transactions() {
+        let mut pending = PendingTransactions::default();
+        pending.insert(HASH, 1_000);
+
+        assert!(pending.contains(&HASH.to_lowercase(), 1_000));
+        assert!(pending.contains(HASH, 1_000 + PENDING_TTL_MS - 1));
+        assert!(!pending.contains(HASH, 1_000 + PENDING_TTL_MS));
+
+        pending.remove(&HASH.to_lowercase());
+        assert!(!pending.contains(HASH, 1_000));
+    }
+
+    #[test]
+    fn test_pending_transactions_are_bounded() {
+        let mut pending = PendingTransactions::default();
+        for i in 0..MAX_PENDING {
+            pending.insert(&format!("0x{i:064x}"), 0);
+        }
+        pending.insert(HASH, 1);
+        assert!(!pending.contains(HASH, 1));
+
+        // Expired entries make room again
+        pending.insert(HASH, PENDING_TTL_MS);
+        assert!(pending.contains(HASH, PENDING_TTL_MS));
+        assert_eq!(pending.submitted_ms.len(), 1);
+    }
+}
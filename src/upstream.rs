@@ -0,0 +1,298 @@
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use worker::*;
+
+use crate::chains::{redact_url, ChainConfig};
+use crate::metrics::{self, Metric};
+use crate::rpc::{RpcError, RpcRequest, RpcResponse, RESOURCE_UNAVAILABLE};
+use crate::utils::parse_hex_to_u64;
+
+/// How long a successful eth_chainId probe is trusted
+const PROBE_TTL_MS: u64 = 10 * 60 * 1000;
+/// Failed probes are retried sooner
+const PROBE_RETRY_MS: u64 = 30 * 1000;
+
+#[derive(Clone)]
+struct ChainIdProbe {
+    reported: std::result::Result<u64, String>,
+    checked_at_ms: u64,
+}
+
+impl ChainIdProbe {
+    fn is_fresh(&self, now: u64) -> bool {
+        let ttl = if self.reported.is_ok() { PROBE_TTL_MS } else { PROBE_RETRY_MS };
+        now.saturating_sub(self.checked_at_ms) < ttl
+    }
+}
+
+thread_local! {
+    // eth_chainId probe results per upstream URL, shared by all requests in this isolate
+    static CHAIN_ID_PROBES: RefCell<HashMap<String, ChainIdProbe>> = RefCell::new(HashMap::new());
+}
+
+/// Outcome of comparing an upstream's eth_chainId with the chain it is configured for
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum ChainIdCheck {
+    Ok,
+    Mismatch { reported_chain_id: u64 },
+    Unreachable { error: String },
+}
+
+impl ChainIdCheck {
+    fn from_probe(reported: &std::result::Result<u64, String>, expected: u64) -> Self {
+        match reported {
+            Ok(id) if *id == expected => ChainIdCheck::Ok,
+            Ok(id) => ChainIdCheck::Mismatch { reported_chain_id: *id },
+            Err(error) => ChainIdCheck::Unreachable { error: error.clone() },
+        }
+    }
+}
+
+/// Upstream response together with where it came from
+pub struct UpstreamResponse {
+    pub response: RpcResponse,
+    /// URL of the upstream that answered, if any
+    pub upstream: Option<String>,
+    /// Whether that upstream's chain id was confirmed to match; only verified data may be cached
+    pub chain_verified: bool,
+    /// Round trip time of the request that answered
+    pub latency_ms: Option<u64>,
+}
+
+/// Check (with caching) that an upstream serves the expected chain
+pub async fn check_upstream_chain_id(upstream_url: &str, expected: u64) -> ChainIdCheck {
+    let now = Date::now().as_millis();
+
+    let cached = CHAIN_ID_PROBES.with(|probes| {
+        probes
+            .borrow()
+            .get(upstream_url)
+            .filter(|probe| probe.is_fresh(now))
+            .cloned()
+    });
+
+    let probe = match cached {
+        Some(probe) => probe,
+        None => {
+            let probe = ChainIdProbe {
+                reported: probe_chain_id(upstream_url).await,
+                checked_at_ms: now,
+            };
+            CHAIN_ID_PROBES.with(|probes| {
+                probes
+                    .borrow_mut()
+                    .insert(upstream_url.to_string(), probe.clone())
+            });
+            probe
+        }
+    };
+
+    let check = ChainIdCheck::from_probe(&probe.reported, expected);
+    if check != ChainIdCheck::Ok {
+        console_log!(
+            "Upstream {} chain id check for chain {}: {:?}",
+            redact_url(upstream_url),
+            expected,
+            check
+        );
+    }
+    check
+}
+
+async fn probe_chain_id(upstream_url: &str) -> std::result::Result<u64, String> {
+    let body = r#"{"jsonrpc":"2.0","method":"eth_chainId","params":[],"id":1}"#;
+
+    let response = send_to_upstream(upstream_url, body)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    match response.result.as_ref().and_then(|v| v.as_str()) {
+        Some(chain_id) => parse_hex_to_u64(chain_id).map_err(|e| e.to_string()),
+        None => Err(format!("eth_chainId returned no result: {:?}", response.error)),
+    }
+}
+
+/// Send a notification upstream without waiting for a JSON body in return
+pub async fn forward_notification(rpc_request: &RpcRequest, chain: &ChainConfig) -> Result<()> {
+    let mut upstream_url = None;
+    for url in &chain.upstreams {
+        if !matches!(check_upstream_chain_id(url, chain.id).await, ChainIdCheck::Mismatch { .. }) {
+            upstream_url = Some(url);
+            break;
+        }
+    }
+    let upstream_url = upstream_url.ok_or("No upstream available for chain")?;
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+
+    let request_body = serde_json::to_string(rpc_request)?;
+
+    let request = Request::new_with_init(
+        upstream_url,
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(request_body.into())),
+    )?;
+
+    let response = Fetch::Request(request).send().await?;
+    console_log!("Upstream notification status: {}", response.status_code());
+
+    Ok(())
+}
+
+/// Send a request to the chain's upstreams in order, failing over on transport errors
+pub async fn proxy_request(rpc_request: &RpcRequest, chain: &ChainConfig) -> Result<RpcResponse> {
+    fetch_upstream(rpc_request, chain)
+        .await
+        .map(|upstream_response| upstream_response.response)
+}
+
+/// Like `proxy_request`, but also reports which upstream answered and whether it was verified.
+/// Upstreams whose eth_chainId does not match the chain are never used.
+pub async fn fetch_upstream(rpc_request: &RpcRequest, chain: &ChainConfig) -> Result<UpstreamResponse> {
+    let request_body = match serde_json::to_string(rpc_request) {
+        Ok(body) => body,
+        Err(e) => {
+            console_log!("ERROR: Failed to serialize RPC request: {:?}", e);
+            return Err(e.to_string().into());
+        }
+    };
+
+    console_log!("Upstream request body: {}", request_body);
+
+    let mut last_error: Error = "No upstream configured".into();
+    let mut mismatches = Vec::new();
+
+    for upstream_url in &chain.upstreams {
+        let chain_verified = match check_upstream_chain_id(upstream_url, chain.id).await {
+            ChainIdCheck::Mismatch { reported_chain_id } => {
+                mismatches.push(format!("{} reports {}", redact_url(upstream_url), reported_chain_id));
+                continue;
+            }
+            check => check == ChainIdCheck::Ok,
+        };
+
+        let started_ms = Date::now().as_millis();
+        let result = send_to_upstream(upstream_url, &request_body).await;
+        let latency_ms = Date::now().as_millis().saturating_sub(started_ms);
+        let chain_label = chain.id_str();
+        metrics::record_latency(&chain_label, &rpc_request.method, latency_ms);
+
+        match result {
+            Ok(response) => {
+                if response.error.is_some() {
+                    metrics::record(Metric::UpstreamRpcError, &chain_label, &rpc_request.method);
+                }
+                return Ok(UpstreamResponse {
+                    response,
+                    upstream: Some(upstream_url.clone()),
+                    chain_verified,
+                    latency_ms: Some(latency_ms),
+                })
+            }
+            Err(e) => {
+                console_log!("ERROR: Upstream {} failed: {:?}", redact_url(upstream_url), e);
+                metrics::record(Metric::UpstreamError, &chain_label, &rpc_request.method);
+                last_error = e;
+            }
+        }
+    }
+
+    if mismatches.len() == chain.upstreams.len() && !mismatches.is_empty() {
+        let error = RpcError::new(
+            RESOURCE_UNAVAILABLE,
+            format!(
+                "Upstream chain id mismatch for chain {}: {}",
+                chain.id,
+                mismatches.join(", ")
+            ),
+        );
+        return Ok(UpstreamResponse {
+            response: RpcResponse::error(rpc_request.response_id(), error),
+            upstream: None,
+            chain_verified: false,
+            latency_ms: None,
+        });
+    }
+
+    Err(last_error)
+}
+
+pub async fn send_to_upstream(upstream_url: &str, request_body: &str) -> Result<RpcResponse> {
+    console_log!("Proxying to upstream: {}", redact_url(upstream_url));
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+
+    let request = Request::new_with_init(
+        upstream_url,
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(request_body.into())),
+    )?;
+
+    let mut response = match Fetch::Request(request).send().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            console_log!("ERROR: Failed to send request to upstream: {:?}", e);
+            return Err(e);
+        }
+    };
+
+    let status = response.status_code();
+    console_log!("Upstream response status: {}", status);
+
+    let rpc_response: RpcResponse = match response.json().await {
+        Ok(json) => json,
+        Err(e) => {
+            console_log!("ERROR: Failed to parse upstream response as JSON-RPC: {:?}", e);
+            return Err(e);
+        }
+    };
+
+    console_log!("Upstream response: {:?}", rpc_response);
+
+    Ok(rpc_response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain_id_check_from_probe() {
+        assert_eq!(ChainIdCheck::from_probe(&Ok(137), 137), ChainIdCheck::Ok);
+        assert_eq!(
+            ChainIdCheck::from_probe(&Ok(1), 137),
+            ChainIdCheck::Mismatch { reported_chain_id: 1 }
+        );
+        assert_eq!(
+            ChainIdCheck::from_probe(&Err("timeout".to_string()), 137),
+            ChainIdCheck::Unreachable { error: "timeout".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_failed_probes_expire_sooner() {
+        let ok = ChainIdProbe { reported: Ok(1), checked_at_ms: 0 };
+        let failed = ChainIdProbe { reported: Err("down".to_string()), checked_at_ms: 0 };
+
+        assert!(ok.is_fresh(PROBE_RETRY_MS));
+        assert!(!ok.is_fresh(PROBE_TTL_MS));
+        assert!(!failed.is_fresh(PROBE_RETRY_MS));
+    }
+
+    #[test]
+    fn test_chain_id_check_serialization() {
+        let check = ChainIdCheck::Mismatch { reported_chain_id: 1 };
+        assert_eq!(
+            serde_json::to_value(&check).unwrap(),
+            serde_json::json!({"status": "mismatch", "reported_chain_id": 1})
+        );
+    }
+}
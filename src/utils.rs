@@ -14,6 +14,12 @@ pub fn parse_hex_to_u64(hex_str: &str) -> Result<u64> {
     }
 }
 
+/// Check if a block identifier is a block hash (0x followed by 64 hex chars)
+/// rather than a block number or tag
+pub fn is_block_hash(block_id: &str) -> bool {
+    block_id.starts_with("0x") && block_id.len() == 66
+}
+
 /// Generate a cache key from the given data
 pub fn generate_cache_key(chain_id: &str, data: &str) -> String {
     use sha2::{Digest, Sha256};
@@ -27,6 +33,45 @@ pub fn generate_cache_key(chain_id: &str, data: &str) -> String {
     format!("{}:{}", chain_id, hex::encode(result))
 }
 
+/// Match a pattern where `*` stands for any run of characters, e.g. `debug_trace*`
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == value;
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if value.len() < first.len() + last.len() || !value.starts_with(first) || !value.ends_with(last) {
+        return false;
+    }
+
+    // Middle parts must appear in order between the prefix and suffix
+    let mut rest = &value[first.len()..value.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Check an `Authorization` header value against the expected bearer token
+pub fn bearer_token_matches(authorization: Option<&str>, expected: &str) -> bool {
+    authorization
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+        .unwrap_or(false)
+}
+
+/// Compare secrets without leaking the position of the first difference through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -39,6 +84,13 @@ mod tests {
         assert!(parse_hex_to_u64("latest").is_err());
     }
 
+    #[test]
+    fn test_is_block_hash() {
+        assert!(is_block_hash("0x1234567890123456789012345678901234567890123456789012345678901234"));
+        assert!(!is_block_hash("0x64"));
+        assert!(!is_block_hash("latest"));
+    }
+
     #[test]
     fn test_generate_cache_key() {
         let key1 = generate_cache_key("1", "test");
@@ -48,5 +100,25 @@ mod tests {
         assert_eq!(key1, key2);
         assert_ne!(key1, key3);
     }
-}
 
+    #[test]
+    fn test_bearer_token_matches() {
+        assert!(bearer_token_matches(Some("Bearer secret"), "secret"));
+        assert!(!bearer_token_matches(Some("Bearer secreT"), "secret"));
+        assert!(!bearer_token_matches(Some("Bearer secret2"), "secret"));
+        assert!(!bearer_token_matches(Some("secret"), "secret"));
+        assert!(!bearer_token_matches(None, "secret"));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("eth_chainId", "eth_chainId"));
+        assert!(!glob_match("eth_chainId", "eth_chainIds"));
+        assert!(glob_match("debug_trace*", "debug_traceBlockByNumber"));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("eth_get*By*", "eth_getBlockByHash"));
+        assert!(!glob_match("eth_get*By*", "eth_getLogs"));
+        assert!(glob_match("*_sign*", "eth_signTypedData_v4"));
+        assert!(!glob_match("ab*ba", "aba"));
+    }
+}
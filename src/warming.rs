@@ -0,0 +1,583 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use worker::*;
+
+use crate::cache::{CacheManager, CacheStatus};
+use crate::chains::ChainRegistry;
+use crate::directives::CacheDirectives;
+use crate::handlers::{handle_cached, HandlerRegistry};
+use crate::index::{CacheIndex, WarmCheckpoint};
+use crate::rpc::RpcRequest;
+use crate::utils::parse_hex_to_u64;
+
+/// Blocks per `eth_getLogs` request when a job doesn't set `chunk_size`
+pub const DEFAULT_CHUNK_SIZE: u64 = 2_000;
+/// Upstream requests per warming run when `max_requests_per_run` is not set
+pub const DEFAULT_MAX_REQUESTS_PER_RUN: usize = 100;
+
+/// A contract (or topic) whose history is pre-loaded into the cache
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WarmJob {
+    /// Chain id or alias
+    pub chain: String,
+    /// `address` of the `eth_getLogs` filter: one address or a list
+    #[serde(default)]
+    pub address: Option<Value>,
+    /// `topics` of the `eth_getLogs` filter
+    #[serde(default)]
+    pub topics: Option<Value>,
+    pub from_block: u64,
+    /// Last block to warm; without it the job follows the chain as blocks become cacheable
+    #[serde(default)]
+    pub to_block: Option<u64>,
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u64,
+    /// Also warm `eth_getBlockReceipts` of every block with matching logs
+    #[serde(default = "default_true")]
+    pub receipts: bool,
+    /// Also warm `eth_getBlockByHash` of every block with matching logs
+    #[serde(default = "default_true")]
+    pub blocks: bool,
+}
+
+fn default_chunk_size() -> u64 {
+    DEFAULT_CHUNK_SIZE
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_requests_per_run() -> usize {
+    DEFAULT_MAX_REQUESTS_PER_RUN
+}
+
+/// Warming jobs loaded from the `CACHE_WARMING` variable:
+///
+/// ```json
+/// {
+///   "jobs": {
+///     "usdc": {
+///       "chain": "1",
+///       "address": "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+///       "topics": ["0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"],
+///       "from_block": 6082465,
+///       "chunk_size": 1000
+///     }
+///   },
+///   "max_requests_per_run": 100,
+///   "request_interval_ms": 50
+/// }
+/// ```
+///
+/// Progress is checkpointed in the D1 index, so warming only runs when it is bound.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WarmingConfig {
+    #[serde(default)]
+    pub jobs: BTreeMap<String, WarmJob>,
+    /// Upstream-bound requests per run, across all jobs
+    #[serde(default = "default_max_requests_per_run")]
+    pub max_requests_per_run: usize,
+    /// Pause after every request that went to the upstream
+    #[serde(default)]
+    pub request_interval_ms: u64,
+}
+
+impl Default for WarmingConfig {
+    fn default() -> Self {
+        Self {
+            jobs: BTreeMap::new(),
+            max_requests_per_run: DEFAULT_MAX_REQUESTS_PER_RUN,
+            request_interval_ms: 0,
+        }
+    }
+}
+
+impl WarmingConfig {
+    /// Load and validate jobs from `CACHE_WARMING`; unset means no warming
+    pub fn from_env(env: &Env, chain_registry: &ChainRegistry) -> Result<Self> {
+        let raw = match env.var("CACHE_WARMING") {
+            Ok(var) => var.to_string(),
+            Err(_) => return Ok(Self::default()),
+        };
+
+        let config = Self::parse(&raw)?;
+        config.validate(chain_registry)?;
+        Ok(config)
+    }
+
+    pub fn parse(raw: &str) -> Result<Self> {
+        if raw.trim().is_empty() {
+            return Ok(Self::default());
+        }
+
+        serde_json::from_str(raw).map_err(|e| Error::RustError(format!("Invalid CACHE_WARMING: {e}")))
+    }
+
+    /// Check the jobs for mistakes, reporting all of them at once
+    pub fn validate(&self, chain_registry: &ChainRegistry) -> Result<()> {
+        let mut errors = Vec::new();
+
+        if self.max_requests_per_run == 0 {
+            errors.push("max_requests_per_run must be greater than 0".to_string());
+        }
+
+        for (name, job) in &self.jobs {
+            let location = format!("jobs.{name}");
+            if chain_registry.resolve_chain_id(&job.chain).is_none() {
+                errors.push(format!("{location}.chain: unknown chain \"{}\"", job.chain));
+            }
+            if job.address.is_none() && job.topics.is_none() {
+                errors.push(format!("{location}: address or topics is required"));
+            }
+            match &job.address {
+                None | Some(Value::String(_)) => {}
+                Some(Value::Array(addresses)) if addresses.iter().all(Value::is_string) => {}
+                Some(_) => errors.push(format!("{location}.address: must be a string or a list of strings")),
+            }
+            if job.topics.as_ref().is_some_and(|topics| !topics.is_array()) {
+                errors.push(format!("{location}.topics: must be a list"));
+            }
+            if job.chunk_size == 0 {
+                errors.push(format!("{location}.chunk_size: must be greater than 0"));
+            }
+            if job.to_block.is_some_and(|to_block| to_block < job.from_block) {
+                errors.push(format!("{location}.to_block: must not be below from_block"));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            errors.sort();
+            Err(Error::RustError(format!("Invalid CACHE_WARMING: {}", errors.join("; "))))
+        }
+    }
+}
+
+impl WarmJob {
+    /// `eth_getLogs` params for one chunk, in the shape indexers send them
+    fn logs_params(&self, from_block: u64, to_block: u64) -> Value {
+        let mut filter = json!({
+            "fromBlock": format!("0x{from_block:x}"),
+            "toBlock": format!("0x{to_block:x}"),
+        });
+        if let Some(address) = &self.address {
+            filter["address"] = address.clone();
+        }
+        if let Some(topics) = &self.topics {
+            filter["topics"] = topics.clone();
+        }
+        json!([filter])
+    }
+}
+
+/// Outcome of one job in a warming run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WarmReport {
+    pub job: String,
+    pub chain_id: u64,
+    /// First block not warmed yet, as checkpointed
+    pub next_block: u64,
+    /// Last block that was cacheable (and in the job's range) during this run
+    pub end_block: Option<u64>,
+    pub requests: usize,
+    /// Requests answered from the cache (warmed before)
+    pub cached: usize,
+    /// Requests that went to the upstream
+    pub fetched: usize,
+    /// The job's `to_block` has been reached
+    pub complete: bool,
+    pub error: Option<String>,
+}
+
+/// Next chunk of at most `chunk_size` blocks starting at `next_block`, if any is left before `end`
+fn next_chunk(next_block: u64, end: Option<u64>, chunk_size: u64) -> Option<(u64, u64)> {
+    let end = end.filter(|end| *end >= next_block)?;
+    Some((next_block, end.min(next_block.saturating_add(chunk_size - 1))))
+}
+
+/// Last block that may be warmed: cacheable at the current head and within the job's range
+fn warm_end(to_block: Option<u64>, head: u64, block_distance: u64) -> Option<u64> {
+    let cacheable = head.checked_sub(block_distance)?;
+    Some(to_block.map_or(cacheable, |to_block| to_block.min(cacheable)))
+}
+
+/// Distinct blocks (number and hash) that logs were emitted in, in block order
+fn log_blocks(logs: &Value) -> Vec<(u64, String)> {
+    let blocks: BTreeSet<(u64, String)> = logs
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|log| {
+            let number = parse_hex_to_u64(log.get("blockNumber")?.as_str()?).ok()?;
+            let hash = log.get("blockHash")?.as_str()?.to_lowercase();
+            Some((number, hash))
+        })
+        .collect();
+    blocks.into_iter().collect()
+}
+
+/// Receipts and block requests for the blocks that logs were emitted in, as configured
+fn block_requests(job: &WarmJob, logs: &Value) -> Vec<(&'static str, Value)> {
+    let mut requests = Vec::new();
+    for (block_number, block_hash) in log_blocks(logs) {
+        if job.receipts {
+            requests.push(("eth_getBlockReceipts", json!([format!("0x{block_number:x}")])));
+        }
+        if job.blocks {
+            requests.push(("eth_getBlockByHash", json!([block_hash, false])));
+        }
+    }
+    requests
+}
+
+/// Charge a request against the run's allowance; cache hits are free
+fn charge(allowance: &mut usize, status: CacheStatus) {
+    if status != CacheStatus::Hit {
+        *allowance = allowance.saturating_sub(1);
+    }
+}
+
+/// Warm every configured job, or only `only`, sending at most `max_requests_per_run` requests
+/// to the upstream.
+/// With `reset`, jobs start over from their `from_block` instead of their checkpoint.
+pub async fn warm(
+    env: &Env,
+    chain_registry: &ChainRegistry,
+    registry: &HandlerRegistry,
+    config: &WarmingConfig,
+    only: Option<&str>,
+    reset: bool,
+) -> Result<Vec<WarmReport>> {
+    let index = match CacheIndex::from_env(env) {
+        Some(index) => index,
+        None => return Err("Cache warming needs the CACHE_INDEX D1 binding".into()),
+    };
+
+    let checkpoints: BTreeMap<String, WarmCheckpoint> = index
+        .checkpoints()
+        .await?
+        .into_iter()
+        .map(|checkpoint| (checkpoint.job.clone(), checkpoint))
+        .collect();
+
+    let mut run = WarmRun {
+        registry,
+        allowance: config.max_requests_per_run,
+        request_interval_ms: config.request_interval_ms,
+    };
+    let mut reports = Vec::new();
+
+    for (name, job) in config.jobs.iter().filter(|(name, _)| only.is_none_or(|only| only == *name)) {
+        if run.allowance == 0 {
+            break;
+        }
+
+        let chain_id = chain_registry.resolve_chain_id(&job.chain).unwrap_or_default();
+        let start = match checkpoints.get(name) {
+            Some(checkpoint) if !reset && checkpoint.chain_id == chain_id => {
+                checkpoint.next_block.max(job.from_block)
+            }
+            _ => job.from_block,
+        };
+        let mut report = WarmReport {
+            job: name.clone(),
+            chain_id,
+            next_block: start,
+            ..WarmReport::default()
+        };
+
+        if let Err(e) = run.warm_job(env, chain_registry, job, &mut report).await {
+            console_log!("ERROR: Warming job {} stopped: {:?}", name, e);
+            report.error = Some(e.to_string());
+        }
+        report.complete = job.to_block.is_some_and(|to_block| report.next_block > to_block);
+
+        if report.next_block != start || reset {
+            index
+                .record_checkpoint(&WarmCheckpoint {
+                    job: name.clone(),
+                    chain_id,
+                    next_block: report.next_block,
+                    updated_at_ms: Date::now().as_millis(),
+                })
+                .await?;
+        }
+
+        console_log!(
+            "Warming job {}: next block {}, {} requests ({} cached, {} fetched)",
+            name,
+            report.next_block,
+            report.requests,
+            report.cached,
+            report.fetched
+        );
+        reports.push(report);
+    }
+
+    Ok(reports)
+}
+
+/// State shared by the jobs of one warming run
+struct WarmRun<'a> {
+    registry: &'a HandlerRegistry,
+    /// Upstream requests left in this run
+    allowance: usize,
+    request_interval_ms: u64,
+}
+
+impl WarmRun<'_> {
+    /// Warm whole chunks from `report.next_block` until the allowance or the cacheable range
+    /// runs out, advancing `report.next_block` past every completed chunk
+    async fn warm_job(
+        &mut self,
+        env: &Env,
+        chain_registry: &ChainRegistry,
+        job: &WarmJob,
+        report: &mut WarmReport,
+    ) -> Result<()> {
+        let chain = match chain_registry.lookup(env, report.chain_id) {
+            Some(chain) => chain,
+            None => return Err(format!("Unknown chain {}", job.chain).into()),
+        };
+        let cache_manager = CacheManager::new(env, &chain)?;
+
+        let head = cache_manager.get_current_block_number().await?;
+        let block_distance = cache_manager
+            .method_policy("eth_getLogs")
+            .block_distance
+            .unwrap_or(chain.block_distance);
+        report.end_block = warm_end(job.to_block, head, block_distance);
+
+        while let Some((from_block, to_block)) = next_chunk(report.next_block, report.end_block, job.chunk_size) {
+            if !self.warm_chunk(&cache_manager, job, from_block, to_block, report).await? {
+                break;
+            }
+            report.next_block = to_block + 1;
+        }
+        Ok(())
+    }
+
+    /// Warm the logs of one chunk, then the receipts and blocks they were emitted in.
+    /// Returns false when the allowance ran out before the chunk was done.
+    async fn warm_chunk(
+        &mut self,
+        cache_manager: &CacheManager,
+        job: &WarmJob,
+        from_block: u64,
+        to_block: u64,
+        report: &mut WarmReport,
+    ) -> Result<bool> {
+        let logs = match self
+            .request(cache_manager, "eth_getLogs", job.logs_params(from_block, to_block), report)
+            .await?
+        {
+            Some(logs) => logs,
+            None => return Ok(false),
+        };
+
+        for (method, params) in block_requests(job, &logs) {
+            if self.request(cache_manager, method, params, report).await?.is_none() {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Send one request through the caching pipeline, so it is only fetched and stored when
+    /// not cached yet. Returns `None` without sending anything once the allowance is spent;
+    /// only requests that reach the upstream are charged, so replaying the cached part of an
+    /// interrupted chunk costs nothing.
+    async fn request(
+        &mut self,
+        cache_manager: &CacheManager,
+        method: &str,
+        params: Value,
+        report: &mut WarmReport,
+    ) -> Result<Option<Value>> {
+        if self.allowance == 0 {
+            return Ok(None);
+        }
+        let handler = match self.registry.get(method) {
+            Some(handler) => handler,
+            None => return Err(format!("Method is not cached: {method}").into()),
+        };
+
+        report.requests += 1;
+
+        let rpc_request = RpcRequest {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params,
+            id: Some(json!(1)),
+        };
+        let (response, outcome) =
+            handle_cached(handler, &rpc_request, cache_manager, CacheDirectives::default()).await?;
+
+        charge(&mut self.allowance, outcome.status);
+        if outcome.status == CacheStatus::Hit {
+            report.cached += 1;
+        } else {
+            report.fetched += 1;
+            if self.request_interval_ms > 0 {
+                Delay::from(Duration::from_millis(self.request_interval_ms)).await;
+            }
+        }
+
+        match response.error {
+            Some(error) => Err(format!("{method} failed: {}", error.message).into()),
+            None => Ok(Some(response.result.unwrap_or(Value::Null))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain_registry() -> ChainRegistry {
+        ChainRegistry::parse(
+            r#"[{"id": 1, "name": "Ethereum", "upstreams": ["https://eth.example/rpc"]}]"#,
+            |_| None,
+            &["eth_getLogs"],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_warming() {
+        let config = WarmingConfig::parse(
+            r#"{
+                "jobs": {
+                    "usdc": {
+                        "chain": "ethereum",
+                        "address": "0xa0b8",
+                        "topics": ["0xddf2", null],
+                        "from_block": 100,
+                        "to_block": 5000
+                    }
+                },
+                "request_interval_ms": 50
+            }"#,
+        )
+        .unwrap();
+        config.validate(&chain_registry()).unwrap();
+        assert_eq!(config.max_requests_per_run, DEFAULT_MAX_REQUESTS_PER_RUN);
+
+        let job = &config.jobs["usdc"];
+        assert_eq!(job.chunk_size, DEFAULT_CHUNK_SIZE);
+        assert!(job.receipts && job.blocks);
+        assert_eq!(
+            job.logs_params(100, 0x1ff),
+            json!([{
+                "fromBlock": "0x64",
+                "toBlock": "0x1ff",
+                "address": "0xa0b8",
+                "topics": ["0xddf2", null],
+            }])
+        );
+    }
+
+    #[test]
+    fn test_invalid_warming() {
+        assert!(WarmingConfig::parse(r#"{"jobs": {}, "rate": 1}"#).is_err());
+
+        let config = WarmingConfig::parse(
+            r#"{
+                "jobs": {"bad": {"chain": "nowhere", "address": 1, "from_block": 10, "to_block": 5, "chunk_size": 0}},
+                "max_requests_per_run": 0
+            }"#,
+        )
+        .unwrap();
+        let message = config.validate(&chain_registry()).unwrap_err().to_string();
+        assert!(message.contains("max_requests_per_run must be greater than 0"));
+        assert!(message.contains("jobs.bad.chain: unknown chain \"nowhere\""));
+        assert!(message.contains("jobs.bad.address: must be a string or a list of strings"));
+        assert!(message.contains("jobs.bad.chunk_size: must be greater than 0"));
+        assert!(message.contains("jobs.bad.to_block: must not be below from_block"));
+
+        let config = WarmingConfig::parse(r#"{"jobs": {"all": {"chain": "1", "from_block": 0}}}"#).unwrap();
+        let message = config.validate(&chain_registry()).unwrap_err().to_string();
+        assert!(message.contains("jobs.all: address or topics is required"));
+    }
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(next_chunk(100, Some(5000), 1000), Some((100, 1099)));
+        assert_eq!(next_chunk(4100, Some(5000), 1000), Some((4100, 5000)));
+        assert_eq!(next_chunk(5001, Some(5000), 1000), None);
+        assert_eq!(next_chunk(0, None, 1000), None);
+
+        // Capped by the cacheable head
+        assert_eq!(warm_end(Some(5000), 10_000, 100), Some(5000));
+        assert_eq!(warm_end(Some(50_000), 10_000, 100), Some(9_900));
+        assert_eq!(warm_end(None, 10_000, 100), Some(9_900));
+        assert_eq!(warm_end(None, 50, 100), None);
+    }
+
+    /// One run over a chunk, with `cached` standing in for the cache: returns whether the
+    /// chunk was completed before the allowance ran out
+    fn simulate_chunk(job: &WarmJob, logs: &Value, cached: &mut BTreeSet<String>, allowance: usize) -> bool {
+        let mut allowance = allowance;
+        let requests = std::iter::once(("eth_getLogs", job.logs_params(100, 199)))
+            .chain(block_requests(job, logs));
+        for (method, params) in requests {
+            if allowance == 0 {
+                return false;
+            }
+            let key = format!("{method}{params}");
+            let status = if cached.contains(&key) { CacheStatus::Hit } else { CacheStatus::Miss };
+            cached.insert(key);
+            charge(&mut allowance, status);
+        }
+        true
+    }
+
+    #[test]
+    fn test_interrupted_chunk_progresses() {
+        let job: WarmJob = serde_json::from_value(json!({
+            "chain": "1",
+            "address": "0xa0b8",
+            "from_block": 100,
+            "chunk_size": 100
+        }))
+        .unwrap();
+        // 10 blocks with logs: 1 logs request and 20 receipt/block requests, with 5 per run
+        let logs: Vec<Value> = (100..110)
+            .map(|block| json!({"blockNumber": format!("0x{block:x}"), "blockHash": format!("0x{block}")}))
+            .collect();
+        let logs = Value::Array(logs);
+        assert_eq!(block_requests(&job, &logs).len(), 20);
+
+        let mut cached = BTreeSet::new();
+        let runs = (1..=10)
+            .find(|_| simulate_chunk(&job, &logs, &mut cached, 5))
+            .expect("chunk never completes");
+        assert_eq!(runs, 5);
+
+        // Without receipts and blocks only the logs are requested
+        let logs_only = WarmJob { receipts: false, blocks: false, ..job };
+        assert!(block_requests(&logs_only, &logs).is_empty());
+    }
+
+    #[test]
+    fn test_log_blocks() {
+        let logs = json!([
+            {"blockNumber": "0x65", "blockHash": "0xBB", "logIndex": "0x0"},
+            {"blockNumber": "0x64", "blockHash": "0xaa", "logIndex": "0x3"},
+            {"blockNumber": "0x65", "blockHash": "0xbb", "logIndex": "0x1"},
+            {"removed": true},
+        ]);
+        assert_eq!(
+            log_blocks(&logs),
+            vec![(100, "0xaa".to_string()), (101, "0xbb".to_string())]
+        );
+        assert!(log_blocks(&Value::Null).is_empty());
+    }
+}
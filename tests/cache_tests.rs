@@ -1,3 +1,5 @@
+#![allow(clippy::useless_vec)]
+
 use serde_json::json;
 
 // This file contains integration tests for the RPC proxy cache functionality